@@ -1,73 +1,456 @@
 //! ACME certificate renewal.
 
-use std::{collections::HashMap, fs, sync::Arc};
+use std::{fmt, fs, process::Command, str::FromStr, thread, time::Duration};
 
-use acme_lib::{persist::FilePersist, Account, Directory, DirectoryUrl};
-use ahash::RandomState;
-use eyre::{eyre, Result};
-use parking_lot::RwLock;
+use acme_lib::{
+    api::{ApiDirectory, ApiDirectoryMeta},
+    order::Auth,
+    persist::{Persist, PersistKey, PersistKind},
+    Account, Directory, DirectoryUrl, RevocationReason,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use eyre::{bail, ensure, eyre, Result, WrapErr};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, oneshot};
+use x509_parser::{
+    certificate::X509Certificate,
+    extensions::{GeneralName, ParsedExtension},
+};
 
-pub struct Challenge {
-    pub path: String,
-    pub proof: String,
-}
+use crate::{
+    jws::AccountKey,
+    settings::{self, KeyType},
+    storage::Storage,
+};
 
-impl Challenge {
-    fn new(token: &str, proof: String) -> Self {
-        Self {
-            path: format!("/.well-known/acme-challenge/{token}"),
-            proof,
-        }
-    }
-}
+pub const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
 
-pub type ChallengeStorage = Arc<RwLock<HashMap<String, Challenge, RandomState>>>;
+/// Name under which acme-lib stores the account key.
+const ACCOUNT_KEY_NAME: &str = "acme_account";
+/// Delay between attempts to set up the account while the ACME server is unreachable.
+const SETUP_RETRY: Duration = Duration::from_mins(1);
 
-pub struct Certificate(acme_lib::Certificate);
+pub struct Certificate {
+    cert_pem: String,
+    key_pem: String,
+    domains: Vec<String>,
+    key_type: KeyType,
+}
 
 impl Certificate {
+    /// Read the host names that the certificate actually covers from its leaf, which might differ
+    /// from the configured ones if the configuration changed after it was issued.
+    fn new(cert_pem: String, key_pem: String, key_type: KeyType) -> Result<Self> {
+        let mut cert = Self {
+            cert_pem,
+            key_pem,
+            domains: Vec::new(),
+            key_type,
+        };
+
+        cert.domains = cert.with_leaf(|leaf| {
+            leaf.tbs_certificate
+                .subject_alternative_name()
+                .map(|(_, san)| {
+                    san.general_names
+                        .iter()
+                        .filter_map(|name| match name {
+                            GeneralName::DNSName(name) => Some(name.to_ascii_lowercase()),
+                            _ => None,
+                        })
+                        .collect()
+                })
+                .unwrap_or_default()
+        })?;
+
+        Ok(cert)
+    }
+
     pub fn certificate(&self) -> &str {
-        self.0.certificate()
+        &self.cert_pem
     }
 
     pub fn private_key(&self) -> &str {
-        self.0.private_key()
+        &self.key_pem
+    }
+
+    /// All host names covered by this certificate, as listed in its subject alternative names.
+    pub fn domains(&self) -> &[String] {
+        &self.domains
+    }
+
+    /// Whether the certificate covers exactly the given host names, in any order.
+    pub fn covers(&self, domains: &[String]) -> bool {
+        self.domains.len() == domains.len()
+            && domains
+                .iter()
+                .all(|domain| self.domains.contains(&domain.to_ascii_lowercase()))
+    }
+
+    pub const fn key_type(&self) -> KeyType {
+        self.key_type
+    }
+
+    fn with_leaf<T>(&self, f: impl FnOnce(&X509Certificate<'_>) -> T) -> Result<T> {
+        let der = rustls_pemfile::certs(&mut self.cert_pem.as_bytes())?
+            .into_iter()
+            .next()
+            .ok_or_else(|| eyre!("certificate chain is empty"))?;
+        let (_, leaf) = x509_parser::parse_x509_certificate(&der)
+            .map_err(|e| eyre!("invalid certificate: {e}"))?;
+
+        Ok(f(&leaf))
+    }
+
+    /// Identifier of the certificate for renewal information requests, made up of the authority
+    /// key identifier and the serial number.
+    fn renewal_id(&self) -> Result<String> {
+        self.with_leaf(|leaf| {
+            let key_id = leaf
+                .extensions()
+                .iter()
+                .find_map(|ext| match ext.parsed_extension() {
+                    ParsedExtension::AuthorityKeyIdentifier(aki) => aki.key_identifier.as_ref(),
+                    _ => None,
+                })?;
+
+            Some(format!(
+                "{}.{}",
+                URL_SAFE_NO_PAD.encode(key_id.0),
+                URL_SAFE_NO_PAD.encode(leaf.tbs_certificate.raw_serial())
+            ))
+        })?
+        .ok_or_else(|| eyre!("certificate has no authority key identifier"))
+    }
+
+    /// Default renewal time, after two thirds of the certificate's lifetime passed.
+    fn default_renewal(&self) -> Result<DateTime<Utc>> {
+        let (not_before, not_after) = self.with_leaf(|leaf| {
+            let validity = leaf.validity();
+            (
+                validity.not_before.timestamp(),
+                validity.not_after.timestamp(),
+            )
+        })?;
+
+        Utc.timestamp_opt(not_before + (not_after - not_before) * 2 / 3, 0)
+            .single()
+            .ok_or_else(|| eyre!("invalid certificate validity"))
+    }
+}
+
+/// Time range suggested for renewing a certificate.
+pub struct RenewalWindow {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// How long to wait before asking the CA again, if it gave a hint.
+    pub retry_after: Option<Duration>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RenewalInfo {
+    suggested_window: SuggestedWindow,
+}
+
+#[derive(Deserialize)]
+struct SuggestedWindow {
+    start: String,
+    end: String,
+}
+
+/// Reasons that subscribers may give when revoking their own certificates.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum RevokeReason {
+    #[default]
+    Unspecified,
+    KeyCompromise,
+    AffiliationChanged,
+    Superseded,
+    CessationOfOperation,
+}
+
+impl FromStr for RevokeReason {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "unspecified" => Self::Unspecified,
+            "key-compromise" => Self::KeyCompromise,
+            "affiliation-changed" => Self::AffiliationChanged,
+            "superseded" => Self::Superseded,
+            "cessation-of-operation" => Self::CessationOfOperation,
+            _ => bail!("unknown revocation reason `{s}`"),
+        })
+    }
+}
+
+impl From<RevokeReason> for RevocationReason {
+    fn from(reason: RevokeReason) -> Self {
+        match reason {
+            RevokeReason::Unspecified => Self::Unspecified,
+            RevokeReason::KeyCompromise => Self::KeyCompromise,
+            RevokeReason::AffiliationChanged => Self::AffiliationChanged,
+            RevokeReason::Superseded => Self::Superseded,
+            RevokeReason::CessationOfOperation => Self::CessationOfOperation,
+        }
     }
 }
 
 pub struct Acme {
-    challenges: ChallengeStorage,
-    account: Account<FilePersist>,
+    account: Account<Storage>,
+    directory: ApiDirectory,
+    storage: Storage,
+    realm: String,
+    dns_hook: Option<String>,
+    dns_propagation_delay: Duration,
+    renewal_info: Option<String>,
 }
 
 impl Acme {
-    pub fn new(challenges: ChallengeStorage, email: &str) -> Result<Self> {
-        fs::create_dir_all("temp")?;
+    /// Set up the account from the settings, registering it at the CA if there is none yet.
+    pub fn new(storage: Storage, settings: &settings::Acme) -> Result<Self> {
+        Self::setup(storage, settings, true)
+    }
 
-        let dir = Directory::from_url(FilePersist::new("temp"), DirectoryUrl::LetsEncryptStaging)?;
-        let account = dir.account(email)?;
+    /// Open the existing account from the settings, failing if it isn't stored or the CA doesn't
+    /// know it.
+    pub fn open(storage: Storage, settings: &settings::Acme) -> Result<Self> {
+        Self::setup(storage, settings, false)
+    }
+
+    fn setup(storage: Storage, settings: &settings::Acme, register: bool) -> Result<Self> {
+        let dir = Directory::from_url(storage.clone(), DirectoryUrl::Other(&settings.directory))?;
+        let directory = dir.api_directory().clone();
+
+        if !register {
+            let persist_key = PersistKey::new(
+                &settings.email,
+                PersistKind::AccountPrivateKey,
+                ACCOUNT_KEY_NAME,
+            );
+            let key = storage
+                .get(&persist_key)?
+                .ok_or_else(|| eyre!("no ACME account stored for {}", settings.email))?;
+
+            // acme-lib registers unknown keys, so check that the CA knows the account first.
+            post(
+                &directory,
+                &AccountKey::from_pem(&key)?,
+                &directory.newAccount,
+                None,
+                &json!({ "onlyReturnExisting": true }),
+            )
+            .wrap_err_with(|| format!("no ACME account registered for {}", settings.email))?;
+        }
+
+        match &settings.eab {
+            Some(_) if !register => {}
+            Some(eab) => register_bound(&storage, &directory, &settings.email, eab)?,
+            None => ensure!(
+                !directory
+                    .meta
+                    .as_ref()
+                    .is_some_and(ApiDirectoryMeta::externalAccountRequired),
+                "the ACME server requires external account binding credentials"
+            ),
+        }
+
+        let account = dir.account(&settings.email)?;
 
         Ok(Self {
-            challenges,
             account,
+            directory,
+            storage,
+            realm: settings.email.clone(),
+            dns_hook: settings.dns_hook.clone(),
+            dns_propagation_delay: Duration::from_secs(settings.dns_propagation_delay),
+            renewal_info: renewal_info_url(&settings.directory),
         })
     }
 
-    pub fn load_certs(
+    /// Determine when the certificate should be renewed, following the CA's renewal information
+    /// (ARI) if supported, or after two thirds of its lifetime otherwise.
+    pub fn renewal_window(&self, cert: &Certificate) -> Result<RenewalWindow> {
+        if let Some(base) = &self.renewal_info {
+            match fetch_renewal_info(base, cert) {
+                Ok(window) => return Ok(window),
+                Err(e) => warn!(
+                    "failed fetching renewal info, using default window: {:?}",
+                    e
+                ),
+            }
+        }
+
+        let renew_at = cert.default_renewal()?;
+
+        Ok(RenewalWindow {
+            start: renew_at,
+            end: renew_at,
+            retry_after: None,
+        })
+    }
+
+    pub fn load_cert(&self, domains: &[String], key_type: KeyType) -> Result<Option<Certificate>> {
+        load_cert(&self.storage, &self.realm, domains, key_type)
+    }
+
+    fn persist_get(&self, kind: PersistKind, name: &str) -> Result<Option<String>> {
+        let key = PersistKey::new(&self.realm, kind, name);
+        Ok(self
+            .storage
+            .get(&key)?
+            .and_then(|v| String::from_utf8(v).ok()))
+    }
+
+    fn persist_put(&self, kind: PersistKind, name: &str, value: &str) -> Result<()> {
+        let key = PersistKey::new(&self.realm, kind, name);
+        self.storage.put(&key, value.as_bytes()).map_err(Into::into)
+    }
+
+    fn persist_remove(&self, kind: PersistKind, name: &str) -> Result<()> {
+        self.storage
+            .remove(&PersistKey::new(&self.realm, kind, name))
+    }
+
+    /// Revoke the stored certificate of a domain group and remove it, so a replacement is ordered
+    /// on the next start. Returns whether there was a certificate to revoke.
+    pub fn revoke(
         &self,
-        domains: impl Iterator<Item = impl AsRef<str>>,
-    ) -> Result<HashMap<String, Certificate, RandomState>> {
-        domains
-            .filter_map(|d| match self.account.certificate(d.as_ref()) {
-                Ok(None) => None,
-                Ok(Some(cert)) => Some(Ok((d.as_ref().to_owned(), Certificate(cert)))),
-                Err(e) => Some(Err(e.into())),
-            })
-            .collect()
+        domains: &[String],
+        key_type: KeyType,
+        reason: RevokeReason,
+    ) -> Result<bool> {
+        let primary = domains
+            .first()
+            .ok_or_else(|| eyre!("no domains for revocation"))?;
+        let name = persist_name(primary, key_type);
+
+        let Some(cert) = self.account.certificate(&name)? else {
+            return Ok(false);
+        };
+
+        self.account.revoke_certificate(&cert, reason.into())?;
+        self.persist_remove(PersistKind::Certificate, &name)?;
+        self.persist_remove(PersistKind::PrivateKey, &name)?;
+
+        Ok(true)
+    }
+
+    /// Replace the account key with a newly generated one, through the CA's key change endpoint.
+    pub fn rotate_key(&self) -> Result<()> {
+        let old_key = self.account_key()?;
+        let account_url = self.account_url(&old_key)?;
+        let new_key = AccountKey::generate()?;
+
+        let url = &self.directory.keyChange;
+        let inner = new_key.sign(
+            json!({ "jwk": new_key.jwk()?, "url": url }),
+            &json!({ "account": account_url, "oldKey": old_key.jwk()? }),
+        )?;
+
+        post(&self.directory, &old_key, url, Some(&account_url), &inner)?;
+        self.persist_put(
+            PersistKind::AccountPrivateKey,
+            ACCOUNT_KEY_NAME,
+            &String::from_utf8(new_key.to_pem()?)?,
+        )
+    }
+
+    /// Change the account's contact address. All stored data is moved along, as the persistence
+    /// is keyed by the email address, so the settings only need to be updated afterwards.
+    pub fn update_email(&self, email: &str) -> Result<()> {
+        let key = self.account_key()?;
+        let account_url = self.account_url(&key)?;
+
+        post(
+            &self.directory,
+            &key,
+            &account_url,
+            Some(&account_url),
+            &json!({ "contact": [format!("mailto:{email}")] }),
+        )?;
+
+        let old_prefix = format!("{}_", realm_id(&self.realm));
+        let new_prefix = format!("{}_", realm_id(email));
+
+        for entry in fs::read_dir(self.storage.dir())? {
+            let path = entry?.path();
+            let Some(rest) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix(&old_prefix))
+            else {
+                continue;
+            };
+
+            fs::rename(&path, path.with_file_name(format!("{new_prefix}{rest}")))?;
+        }
+
+        Ok(())
+    }
+
+    /// Permanently deactivate the account. The CA rejects any further requests from it.
+    pub fn deactivate(&self) -> Result<()> {
+        let key = self.account_key()?;
+        let account_url = self.account_url(&key)?;
+
+        post(
+            &self.directory,
+            &key,
+            &account_url,
+            Some(&account_url),
+            &json!({ "status": "deactivated" }),
+        )
+        .map(drop)
+    }
+
+    fn account_key(&self) -> Result<AccountKey> {
+        AccountKey::from_pem(self.account.acme_private_key_pem().as_bytes())
+    }
+
+    /// Look up the account URL, which identifies the account in signed requests.
+    fn account_url(&self, key: &AccountKey) -> Result<String> {
+        let resp = post(
+            &self.directory,
+            key,
+            &self.directory.newAccount,
+            None,
+            &json!({ "onlyReturnExisting": true }),
+        )?;
+
+        resp.header("Location")
+            .map(ToOwned::to_owned)
+            .ok_or_else(|| eyre!("ACME server didn't return the account URL"))
     }
 
-    pub fn request(&self, domain: &str) -> Result<Certificate> {
-        let mut ord = self.account.new_order(domain, &[])?;
+    /// Request a single certificate for all given domains, using the first one as primary name
+    /// and the rest as alternative names.
+    pub fn request(&self, domains: &[String], key_type: KeyType) -> Result<Certificate> {
+        let (primary, alt_names) = domains
+            .split_first()
+            .ok_or_else(|| eyre!("no domains for cert order"))?;
+        let alt_names = alt_names.iter().map(String::as_str).collect::<Vec<_>>();
+        let name = persist_name(primary, key_type);
+
+        // With shared storage, another instance might be ordering the same certificate already,
+        // in which case its certificate is used once it's done.
+        let previous = self.load_cert(domains, key_type)?;
+        let lock = self.storage.lock(&name)?;
+        if lock.waited() {
+            if let Some(cert) = self.load_cert(domains, key_type)? {
+                let changed = previous.is_none_or(|prev| prev.cert_pem != cert.cert_pem);
+                if changed && cert.covers(domains) {
+                    info!("using certificate for {} ordered by another instance", name);
+                    return Ok(cert);
+                }
+            }
+        }
+
+        let mut ord = self.account.new_order(primary, &alt_names)?;
 
         let csr = loop {
             if let Some(csr) = ord.confirm_validations() {
@@ -75,28 +458,512 @@ impl Acme {
             }
 
             let auths = ord.authorizations()?;
-            let chall = auths
-                .get(0)
-                .ok_or_else(|| eyre!("no authorizations in cert order"))?
-                .http_challenge();
-
-            self.challenges.write().insert(
-                domain.to_owned(),
-                Challenge::new(chall.http_token(), chall.http_proof()),
-            );
+            if auths.is_empty() {
+                bail!("no authorizations in cert order");
+            }
 
-            chall.validate(5000)?;
+            let (dns, http) = auths
+                .iter()
+                .filter(|auth| auth.need_challenge())
+                .partition::<Vec<_>, _>(|auth| auth.api_auth().wildcard());
 
-            ord.refresh()?;
+            self.validate_http(&http)?;
+            for auth in dns {
+                self.validate_dns(auth)?;
+            }
 
-            self.challenges.write().remove(domain);
+            ord.refresh()?;
         };
 
-        let pkey = acme_lib::create_p384_key();
+        let pkey = match key_type {
+            KeyType::EcdsaP256 => acme_lib::create_p256_key(),
+            KeyType::EcdsaP384 => acme_lib::create_p384_key(),
+            KeyType::Rsa2048 => acme_lib::create_rsa_key(2048),
+            KeyType::Rsa4096 => acme_lib::create_rsa_key(4096),
+        };
         let ord = csr.finalize_pkey(pkey, 5000)?;
 
         let cert = ord.download_and_save_cert()?;
 
-        Ok(Certificate(cert))
+        // acme-lib only stores a single certificate per primary name, so they're saved again
+        // under a name that includes the key type, allowing several key types for one domain.
+        self.persist_put(PersistKind::PrivateKey, &name, cert.private_key())?;
+        self.persist_put(PersistKind::Certificate, &name, cert.certificate())?;
+
+        Certificate::new(
+            cert.certificate().to_owned(),
+            cert.private_key().to_owned(),
+            key_type,
+        )
+    }
+
+    /// Publish the tokens of all authorizations at once and validate them one after another.
+    fn validate_http(&self, auths: &[&Auth<Storage>]) -> Result<()> {
+        let challenges = auths
+            .iter()
+            .map(|auth| auth.http_challenge())
+            .collect::<Vec<_>>();
+        let tokens = challenges
+            .iter()
+            .map(|chall| chall.http_token().to_owned())
+            .collect::<Vec<_>>();
+
+        let result = challenges
+            .iter()
+            .try_for_each(|chall| {
+                self.storage
+                    .add_challenge(chall.http_token(), &chall.http_proof())
+            })
+            .and_then(|()| {
+                challenges
+                    .into_iter()
+                    .try_for_each(|chall| chall.validate(5000).map_err(Into::into))
+            });
+
+        for token in &tokens {
+            self.storage.remove_challenge(token);
+        }
+
+        result
+    }
+
+    /// Wildcard certificates can only be validated through the DNS-01 challenge, which is
+    /// delegated to the configured DNS hook.
+    fn validate_dns(&self, auth: &Auth<Storage>) -> Result<()> {
+        let hook = self.dns_hook.as_deref().ok_or_else(|| {
+            eyre!(
+                "a DNS hook is required to validate the wildcard domain *.{}",
+                auth.domain_name()
+            )
+        })?;
+
+        let chall = auth.dns_challenge();
+        let record = format!("_acme-challenge.{}.", auth.domain_name());
+        let proof = chall.dns_proof();
+
+        run_dns_hook(hook, "present", &record, &proof)?;
+        thread::sleep(self.dns_propagation_delay);
+        let result = chall.validate(5000);
+        run_dns_hook(hook, "cleanup", &record, &proof)?;
+
+        result.map_err(Into::into)
+    }
+}
+
+type Job = Box<dyn FnOnce(&Acme) + Send>;
+
+/// Handle to an [`Acme`] instance that lives on a dedicated thread. All ACME operations are
+/// blocking, so they run on that thread one after another, without ever stalling the async
+/// runtime that handles requests.
+#[derive(Clone)]
+pub struct AcmeHandle {
+    jobs: mpsc::UnboundedSender<Job>,
+    storage: Storage,
+    realm: String,
+}
+
+impl AcmeHandle {
+    /// Create the [`Acme`] instance on a new worker thread without waiting for it. While the ACME
+    /// server is unreachable, setting up the account is retried and jobs wait in the queue, so
+    /// stored certificates can be served in the meantime.
+    pub fn spawn(storage: Storage, settings: settings::Acme) -> Result<Self> {
+        let realm = settings.email.clone();
+
+        Self::start(storage.clone(), realm, move || loop {
+            match Acme::new(storage.clone(), &settings) {
+                Ok(acme) => return Some(acme),
+                Err(e) => {
+                    warn!(
+                        "failed setting up ACME account, retrying in {:?}: {:?}",
+                        SETUP_RETRY, e
+                    );
+                    thread::sleep(SETUP_RETRY);
+                }
+            }
+        })
+    }
+
+    /// Open the existing account on a new worker thread, waiting until it's ready. Unlike
+    /// [`Self::spawn`], this fails right away and never registers a new account.
+    pub async fn open(storage: Storage, settings: settings::Acme) -> Result<Self> {
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let realm = settings.email.clone();
+
+        let handle = Self::start(storage.clone(), realm, move || {
+            match Acme::open(storage, &settings) {
+                Ok(acme) => {
+                    ready_tx.send(Ok(())).ok();
+                    Some(acme)
+                }
+                Err(e) => {
+                    ready_tx.send(Err(e)).ok();
+                    None
+                }
+            }
+        })?;
+
+        ready_rx.await??;
+
+        Ok(handle)
+    }
+
+    fn start<F>(storage: Storage, realm: String, setup: F) -> Result<Self>
+    where
+        F: FnOnce() -> Option<Acme> + Send + 'static,
+    {
+        let (jobs, mut rx) = mpsc::unbounded_channel::<Job>();
+
+        thread::Builder::new()
+            .name("acme".to_owned())
+            .spawn(move || {
+                let Some(acme) = setup() else {
+                    return;
+                };
+
+                while let Some(job) = rx.blocking_recv() {
+                    job(&acme);
+                }
+            })?;
+
+        Ok(Self {
+            jobs,
+            storage,
+            realm,
+        })
+    }
+
+    /// Load all existing certificates for the given domain groups, with one certificate for each
+    /// of the group's key types. They're read straight from the storage, so this works before the
+    /// account is set up.
+    pub fn load_certs(&self, groups: &[settings::Certificate]) -> Result<Vec<Certificate>> {
+        let mut certs = Vec::new();
+
+        for group in groups {
+            for &key_type in &group.key_types {
+                if let Some(cert) = load_cert(&self.storage, &self.realm, &group.domains, key_type)?
+                {
+                    certs.push(cert);
+                }
+            }
+        }
+
+        Ok(certs)
+    }
+
+    /// Run a function on the worker thread and wait for its result.
+    pub async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Acme) -> Result<T> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+
+        self.jobs
+            .send(Box::new(move |acme| {
+                tx.send(f(acme)).ok();
+            }))
+            .map_err(|_| eyre!("ACME worker stopped"))?;
+
+        rx.await?
+    }
+
+    pub async fn request(&self, domains: Vec<String>, key_type: KeyType) -> Result<Certificate> {
+        self.run(move |acme| acme.request(&domains, key_type)).await
+    }
+}
+
+/// Point in time from the `Retry-After` header of a failed request, attached to its error.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryAfter(DateTime<Utc>);
+
+impl RetryAfter {
+    /// Parse the header value, which is either a number of seconds or an HTTP date.
+    fn parse(value: &str, now: DateTime<Utc>) -> Option<Self> {
+        let value = value.trim();
+        let at = match value.parse::<u32>() {
+            Ok(secs) => now + chrono::Duration::seconds(secs.into()),
+            Err(_) => DateTime::parse_from_rfc2822(value).ok()?.into(),
+        };
+
+        Some(Self(at))
+    }
+}
+
+impl fmt::Display for RetryAfter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "retry after {}", self.0)
+    }
+}
+
+/// Extract the point in time after which the CA accepts new orders again, if the error was caused
+/// by a rate limit. The `Retry-After` header is only known for the crate's own requests, as
+/// acme-lib drops the headers of failed orders, which leaves the problem document for those.
+pub fn rate_limited_until(err: &eyre::Report) -> Option<DateTime<Utc>> {
+    if let Some(RetryAfter(until)) = err.downcast_ref::<RetryAfter>() {
+        return Some(*until);
+    }
+
+    let Some(acme_lib::Error::ApiProblem(problem)) = err.downcast_ref::<acme_lib::Error>() else {
+        return None;
+    };
+
+    if !problem._type.ends_with(":rateLimited") {
+        return None;
+    }
+
+    // Let's Encrypt describes the end of the limit like `retry after 2023-01-01 12:00:00 UTC`.
+    let retry_after = problem
+        .detail
+        .as_deref()
+        .and_then(|detail| detail.split_once("retry after "))
+        .and_then(|(_, rest)| rest.get(..19))
+        .and_then(|ts| NaiveDateTime::parse_from_str(ts, "%Y-%m-%d %H:%M:%S").ok())
+        .map(|ts| DateTime::from_utc(ts, Utc));
+
+    // Without a hint, wait at least as long as the shortest Let's Encrypt limit window.
+    Some(retry_after.unwrap_or_else(|| Utc::now() + chrono::Duration::hours(1)))
+}
+
+fn renewal_info_url(directory: &str) -> Option<String> {
+    let resp = ureq::get(directory).call();
+    if !resp.ok() {
+        warn!("failed fetching ACME directory: {}", resp.status_line());
+        return None;
+    }
+
+    let directory = serde_json::from_str::<serde_json::Value>(&resp.into_string().ok()?).ok()?;
+
+    directory
+        .get("renewalInfo")?
+        .as_str()
+        .map(|url| url.trim_end_matches('/').to_owned())
+}
+
+fn fetch_renewal_info(base: &str, cert: &Certificate) -> Result<RenewalWindow> {
+    let resp = ureq::get(&format!("{base}/{}", cert.renewal_id()?)).call();
+    ensure!(
+        resp.ok(),
+        "renewal info request failed: {}",
+        resp.status_line()
+    );
+
+    let retry_after = resp
+        .header("Retry-After")
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs);
+    let info = serde_json::from_str::<RenewalInfo>(&resp.into_string()?)?;
+
+    Ok(RenewalWindow {
+        start: DateTime::parse_from_rfc3339(&info.suggested_window.start)?.into(),
+        end: DateTime::parse_from_rfc3339(&info.suggested_window.end)?.into(),
+        retry_after,
+    })
+}
+
+fn load_cert(
+    storage: &Storage,
+    realm: &str,
+    domains: &[String],
+    key_type: KeyType,
+) -> Result<Option<Certificate>> {
+    let Some(primary) = domains.first() else {
+        return Ok(None);
+    };
+
+    let name = persist_name(primary, key_type);
+    let get = |kind| -> Result<Option<String>> {
+        Ok(storage
+            .get(&PersistKey::new(realm, kind, &name))?
+            .and_then(|v| String::from_utf8(v).ok()))
+    };
+
+    match (
+        get(PersistKind::PrivateKey)?,
+        get(PersistKind::Certificate)?,
+    ) {
+        (Some(key_pem), Some(cert_pem)) => Certificate::new(cert_pem, key_pem, key_type).map(Some),
+        _ => Ok(None),
+    }
+}
+
+pub fn persist_name(primary: &str, key_type: KeyType) -> String {
+    format!("{primary}_{}", key_type.as_str())
+}
+
+/// Send a signed request, identifying the account either through its URL or, if not known
+/// yet, its public key.
+fn post(
+    directory: &ApiDirectory,
+    key: &AccountKey,
+    url: &str,
+    account_url: Option<&str>,
+    payload: &Value,
+) -> Result<ureq::Response> {
+    let nonce = ureq::head(&directory.newNonce)
+        .call()
+        .header("Replay-Nonce")
+        .map(ToOwned::to_owned)
+        .ok_or_else(|| eyre!("ACME server didn't return a nonce"))?;
+
+    let mut protected = json!({ "nonce": nonce, "url": url });
+    match account_url {
+        Some(account_url) => protected["kid"] = account_url.into(),
+        None => protected["jwk"] = key.jwk()?,
+    }
+
+    let resp = ureq::post(url)
+        .set("Content-Type", "application/jose+json")
+        .send_string(&key.sign(protected, payload)?.to_string());
+
+    if !resp.ok() {
+        let status = resp.status_line().to_owned();
+        let retry_after = resp
+            .header("Retry-After")
+            .and_then(|v| RetryAfter::parse(v, Utc::now()));
+        let err = eyre!(
+            "ACME request to {url} failed ({status}): {}",
+            resp.into_string().unwrap_or_default()
+        );
+
+        return Err(match retry_after {
+            Some(retry_after) => err.wrap_err(retry_after),
+            None => err,
+        });
+    }
+
+    Ok(resp)
+}
+
+/// Register a new account that is bound to an existing account at the CA, unless there is
+/// already an account key. Afterwards, acme-lib picks up the stored key like any other account.
+fn register_bound(
+    storage: &Storage,
+    directory: &ApiDirectory,
+    email: &str,
+    eab: &settings::Eab,
+) -> Result<()> {
+    let persist_key = PersistKey::new(email, PersistKind::AccountPrivateKey, ACCOUNT_KEY_NAME);
+    if storage.get(&persist_key)?.is_some() {
+        return Ok(());
+    }
+
+    let key = AccountKey::generate()?;
+    let url = &directory.newAccount;
+    let binding = key.external_account_binding(&eab.key_id, &eab.hmac_key, url)?;
+
+    post(
+        directory,
+        &key,
+        url,
+        None,
+        &json!({
+            "contact": [format!("mailto:{email}")],
+            "termsOfServiceAgreed": true,
+            "externalAccountBinding": binding,
+        }),
+    )?;
+
+    storage
+        .put(&persist_key, &key.to_pem()?)
+        .map_err(Into::into)
+}
+
+/// Hashed form of a persistence realm, as used in the stored file names.
+fn realm_id(realm: &str) -> u64 {
+    PersistKey::new(realm, PersistKind::AccountPrivateKey, ACCOUNT_KEY_NAME).realm
+}
+
+fn run_dns_hook(hook: &str, action: &str, record: &str, proof: &str) -> Result<()> {
+    let status = Command::new(hook).args([action, record, proof]).status()?;
+    if !status.success() {
+        bail!("DNS hook failed to {action} record {record} ({status})");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn certificate(names: &[&str]) -> Certificate {
+        let cert = rcgen::generate_simple_self_signed(
+            names
+                .iter()
+                .map(|&name| name.to_owned())
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+
+        Certificate::new(
+            cert.serialize_pem().unwrap(),
+            cert.serialize_private_key_pem(),
+            KeyType::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn domains_from_leaf() {
+        let cert = certificate(&["Example.com", "www.example.com"]);
+
+        assert_eq!(["example.com", "www.example.com"], cert.domains());
+    }
+
+    #[test]
+    fn covers_same_names_in_any_order() {
+        let cert = certificate(&["example.com", "www.example.com"]);
+
+        assert!(cert.covers(&["www.example.com".to_owned(), "EXAMPLE.com".to_owned()]));
+        assert!(!cert.covers(&["example.com".to_owned()]));
+        assert!(!cert.covers(&[
+            "example.com".to_owned(),
+            "www.example.com".to_owned(),
+            "api.example.com".to_owned(),
+        ]));
+    }
+
+    fn rate_limited(detail: &str) -> eyre::Report {
+        acme_lib::Error::ApiProblem(acme_lib::api::ApiProblem {
+            _type: "urn:ietf:params:acme:error:rateLimited".to_owned(),
+            detail: Some(detail.to_owned()),
+            subproblems: None,
+        })
+        .into()
+    }
+
+    #[test]
+    fn parse_retry_after() {
+        let now = Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap();
+
+        assert_eq!(
+            Some(now + chrono::Duration::seconds(120)),
+            RetryAfter::parse("120", now).map(|r| r.0)
+        );
+        assert_eq!(
+            Some(Utc.with_ymd_and_hms(2023, 1, 2, 8, 30, 0).unwrap()),
+            RetryAfter::parse("Mon, 02 Jan 2023 08:30:00 GMT", now).map(|r| r.0)
+        );
+        assert!(RetryAfter::parse("soon", now).is_none());
+    }
+
+    #[test]
+    fn rate_limit_prefers_retry_after_header() {
+        let header = Utc.with_ymd_and_hms(2023, 1, 2, 8, 30, 0).unwrap();
+        let err = rate_limited("too many certificates, retry after 2023-01-01 18:00:00 UTC")
+            .wrap_err(RetryAfter(header));
+
+        assert_eq!(Some(header), rate_limited_until(&err));
+    }
+
+    #[test]
+    fn rate_limit_from_detail() {
+        let err = rate_limited("too many certificates, retry after 2023-01-01 18:00:00 UTC");
+
+        assert_eq!(
+            Some(Utc.with_ymd_and_hms(2023, 1, 1, 18, 0, 0).unwrap()),
+            rate_limited_until(&err)
+        );
+        assert!(rate_limited_until(&eyre!("connection refused")).is_none());
     }
 }
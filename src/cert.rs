@@ -2,20 +2,25 @@
 
 use std::{
     collections::HashMap,
-    fs::File,
+    fs::{self, File},
     io::{prelude::*, BufReader},
+    path::Path,
     sync::Arc,
+    time::{Duration, SystemTime},
 };
 
 use ahash::RandomState;
 use arc_swap::ArcSwap;
-use eyre::Result;
+use eyre::{bail, eyre, Result, WrapErr};
+use log::{info, warn};
 use rustls::{
-    server::{ResolvesServerCert, ResolvesServerCertUsingSni},
-    sign::{self, CertifiedKey},
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey, SigningKey},
+    SignatureAlgorithm, SignatureScheme,
 };
+use tokio::sync::mpsc;
 
-use crate::acme;
+use crate::{acme, settings};
 
 pub struct Resolver<T: ResolvesServerCert>(ArcSwap<T>);
 
@@ -24,55 +29,535 @@ impl<T: ResolvesServerCert> Resolver<T> {
         Self(ArcSwap::from_pointee(inner))
     }
 
+    pub fn load(&self) -> Arc<T> {
+        self.0.load_full()
+    }
+
     pub fn swap(&self, inner: T) {
         self.0.store(Arc::new(inner));
     }
 }
 
+impl<T: ResolvesServerCert + Clone> Resolver<T> {
+    /// Apply changes to a copy of the current inner resolver and swap it in. The function might
+    /// be called several times if other updates happen concurrently.
+    pub fn update(&self, f: impl Fn(&mut T)) {
+        self.0.rcu(|current| {
+            let mut inner = T::clone(current);
+            f(&mut inner);
+            inner
+        });
+    }
+}
+
 impl<T: ResolvesServerCert> ResolvesServerCert for Resolver<T> {
-    fn resolve(
-        &self,
-        client_hello: rustls::server::ClientHello<'_>,
-    ) -> Option<Arc<rustls::sign::CertifiedKey>> {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
         self.0.load().resolve(client_hello)
     }
 }
 
-pub fn load(
-    acme_certs: &HashMap<String, acme::Certificate, RandomState>,
-) -> Result<ResolvesServerCertUsingSni> {
-    let certkey = load_certkey(
-        File::open("localhost.pem")?,
-        File::open("localhost-key.pem")?,
-    )?;
+/// Resolver that selects a certificate by the SNI name of the client, falling back to a wildcard
+/// certificate (`*.example.com`) if no certificate for the exact name exists.
+///
+/// Each name can have several certificates with different key types, in which case the first one
+/// that is usable with the client's signature schemes is picked, preferring ECDSA over RSA.
+///
+/// Configured names that don't have a certificate yet are served a placeholder until the first one
+/// is issued. If the client sends no SNI name or it's unknown, the fallback certificate is used, if
+/// any. Unknown names are additionally reported for on-demand certificates, if enabled.
+#[derive(Clone, Default)]
+pub struct SniResolver {
+    by_name: HashMap<String, Vec<Arc<CertifiedKey>>, RandomState>,
+    placeholders: HashMap<String, Arc<CertifiedKey>, RandomState>,
+    fallback: Option<Arc<CertifiedKey>>,
+    on_demand: Option<mpsc::Sender<String>>,
+}
+
+impl SniResolver {
+    pub fn add(&mut self, name: &str, certkey: Arc<CertifiedKey>) {
+        let certkeys = self.by_name.entry(name.to_ascii_lowercase()).or_default();
+        certkeys.push(certkey);
+        certkeys.sort_by_key(|ck| preference(ck.key.algorithm()));
+    }
+
+    /// Replace all certificates of a name with the given one.
+    pub fn replace(&mut self, name: &str, certkey: Arc<CertifiedKey>) {
+        self.by_name
+            .insert(name.to_ascii_lowercase(), vec![certkey]);
+    }
+
+    /// Replace the certificate of a name that has the same key algorithm as the given one, keeping
+    /// certificates of other key types.
+    pub fn renew(&mut self, name: &str, certkey: Arc<CertifiedKey>) {
+        let algorithm = certkey.key.algorithm();
+        let certkeys = self.by_name.entry(name.to_ascii_lowercase()).or_default();
+        certkeys.retain(|ck| ck.key.algorithm() != algorithm);
+        certkeys.push(certkey);
+        certkeys.sort_by_key(|ck| preference(ck.key.algorithm()));
+    }
+
+    pub fn set_on_demand(&mut self, on_demand: mpsc::Sender<String>) {
+        self.on_demand = Some(on_demand);
+    }
 
-    let mut resolver = ResolvesServerCertUsingSni::new();
-    resolver.add("localhost", certkey)?;
+    /// All distinct certificates of the resolver, including the fallback.
+    pub fn certkeys(&self) -> Vec<Arc<CertifiedKey>> {
+        let mut certkeys = Vec::<Arc<CertifiedKey>>::new();
 
-    for (domain, acme_cert) in acme_certs {
+        for certkey in self.by_name.values().flatten().chain(&self.fallback) {
+            if !certkeys.iter().any(|ck| Arc::ptr_eq(ck, certkey)) {
+                certkeys.push(certkey.clone());
+            }
+        }
+
+        certkeys
+    }
+
+    /// Replace every occurrence of the certificate with a copy that carries the OCSP response.
+    pub fn staple(&mut self, certkey: &Arc<CertifiedKey>, ocsp: Vec<u8>) {
+        let stapled = Arc::new(CertifiedKey {
+            ocsp: Some(ocsp),
+            ..CertifiedKey::clone(certkey)
+        });
+
+        for ck in self
+            .by_name
+            .values_mut()
+            .flatten()
+            .chain(&mut self.fallback)
+        {
+            if Arc::ptr_eq(ck, certkey) {
+                *ck = stapled.clone();
+            }
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        lookup(&self.by_name, &name).is_some() || lookup(&self.placeholders, &name).is_some()
+    }
+
+    pub fn get(&self, name: &str, schemes: &[SignatureScheme]) -> Option<Arc<CertifiedKey>> {
+        let name = name.to_ascii_lowercase();
+
+        lookup(&self.by_name, &name)
+            .and_then(|certkeys| {
+                certkeys
+                    .iter()
+                    .find(|ck| ck.key.choose_scheme(schemes).is_some())
+            })
+            .or_else(|| lookup(&self.placeholders, &name))
+            .cloned()
+    }
+
+    /// Certificate for a client's SNI name and signature schemes, also for handshakes that don't
+    /// go through rustls' `ClientHello`, like QUIC ones.
+    pub fn select(
+        &self,
+        name: Option<&str>,
+        schemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        let Some(name) = name else {
+            return self.fallback.clone();
+        };
+
+        if let Some(on_demand) = &self.on_demand {
+            if !self.contains(name) {
+                // The handshake can't wait for the order, so the fallback is served meanwhile.
+                // If the queue is full, the name is dropped and reported again on the next try.
+                on_demand.try_send(name.to_ascii_lowercase()).ok();
+            }
+        }
+
+        self.get(name, schemes).or_else(|| self.fallback.clone())
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.select(client_hello.server_name(), client_hello.signature_schemes())
+    }
+}
+
+const fn preference(algorithm: SignatureAlgorithm) -> u8 {
+    match algorithm {
+        SignatureAlgorithm::ECDSA => 0,
+        SignatureAlgorithm::ED25519 => 1,
+        SignatureAlgorithm::RSA => 2,
+        _ => 3,
+    }
+}
+
+/// Look up a host name in a map keyed by exact or wildcard names. An exact match always takes
+/// precedence and a wildcard only covers a single label, as defined in RFC 6125.
+pub fn lookup<'a, V>(map: &'a HashMap<String, V, RandomState>, name: &str) -> Option<&'a V> {
+    map.get(name).or_else(|| {
+        let (_, parent) = name.split_once('.')?;
+        map.get(&format!("*.{parent}"))
+    })
+}
+
+pub fn load(
+    acme_groups: &[settings::Certificate],
+    acme_certs: &[acme::Certificate],
+    static_certs: &[settings::Certificate],
+    fallback: &settings::Fallback,
+) -> Result<SniResolver> {
+    let mut resolver = SniResolver {
+        fallback: load_fallback(fallback)?.map(Arc::new),
+        ..SniResolver::default()
+    };
+
+    if Path::new("localhost.pem").exists() && Path::new("localhost-key.pem").exists() {
         let certkey = load_certkey(
-            acme_cert.certificate().as_bytes(),
-            acme_cert.private_key().as_bytes(),
-        )?;
+            File::open("localhost.pem")?,
+            File::open("localhost-key.pem")?,
+        )
+        .wrap_err("failed loading localhost certificate")?;
+
+        resolver.add("localhost", Arc::new(certkey));
+    }
+
+    if matches!(fallback, settings::Fallback::SelfSigned) {
+        for group in acme_groups {
+            if acme_certs.iter().any(|c| c.covers(&group.domains)) {
+                continue;
+            }
 
-        resolver.add(domain, certkey)?;
+            let certkey = Arc::new(self_signed(group.domains.clone())?);
+
+            for domain in &group.domains {
+                resolver
+                    .placeholders
+                    .insert(domain.to_ascii_lowercase(), certkey.clone());
+            }
+        }
+    }
+
+    for acme_cert in acme_certs {
+        let certkey = Arc::new(
+            load_certkey(
+                acme_cert.certificate().as_bytes(),
+                acme_cert.private_key().as_bytes(),
+            )
+            .wrap_err_with(|| {
+                format!("failed loading certificate for {:?}", acme_cert.domains())
+            })?,
+        );
+
+        for domain in acme_cert.domains() {
+            resolver.add(domain, certkey.clone());
+        }
+    }
+
+    for static_cert in static_certs {
+        let certkey = Arc::new(load_static(static_cert).wrap_err_with(|| {
+            format!(
+                "failed loading static certificate for {:?}",
+                static_cert.domains
+            )
+        })?);
+
+        for domain in &static_cert.domains {
+            resolver.replace(domain, certkey.clone());
+        }
     }
 
     Ok(resolver)
 }
 
+pub fn load_acme(acme_cert: &acme::Certificate) -> Result<CertifiedKey> {
+    load_certkey(
+        acme_cert.certificate().as_bytes(),
+        acme_cert.private_key().as_bytes(),
+    )
+    .wrap_err_with(|| format!("failed loading certificate for {:?}", acme_cert.domains()))
+}
+
+fn load_fallback(fallback: &settings::Fallback) -> Result<Option<CertifiedKey>> {
+    Ok(match fallback {
+        settings::Fallback::SelfSigned => Some(self_signed(vec!["charon.invalid".to_owned()])?),
+        settings::Fallback::Static { cert, key } => Some(
+            load_certkey(File::open(cert)?, File::open(key)?)
+                .wrap_err("failed loading fallback certificate")?,
+        ),
+        settings::Fallback::Reject => None,
+    })
+}
+
+/// Generate an in-memory self-signed certificate for the given names, allowing to complete
+/// handshakes for domains that have no (valid) certificate yet.
+fn self_signed(names: Vec<String>) -> Result<CertifiedKey> {
+    let cert = rcgen::generate_simple_self_signed(names)?;
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| eyre!("unsupported self-signed private key type"))?;
+
+    Ok(CertifiedKey::new(
+        vec![rustls::Certificate(cert.serialize_der()?)],
+        key,
+    ))
+}
+
+/// Periodically check the files of static certificates for changes and swap the reloaded
+/// certificates into the resolver. Files that fail to load keep the previous certificate active.
+pub async fn watch(resolver: Arc<Resolver<SniResolver>>, static_certs: Vec<settings::Certificate>) {
+    let mut modified = static_certs.iter().map(modified_time).collect::<Vec<_>>();
+    let mut interval = tokio::time::interval(Duration::from_secs(10));
+
+    loop {
+        interval.tick().await;
+
+        for (static_cert, last) in static_certs.iter().zip(&mut modified) {
+            let current = modified_time(static_cert);
+            if current == *last {
+                continue;
+            }
+
+            *last = current;
+
+            match load_static(static_cert) {
+                Ok(certkey) => {
+                    let certkey = Arc::new(certkey);
+
+                    resolver.update(|inner| {
+                        for domain in &static_cert.domains {
+                            inner.replace(domain, certkey.clone());
+                        }
+                    });
+
+                    info!("reloaded static certificate for {:?}", static_cert.domains);
+                }
+                Err(e) => warn!(
+                    "failed reloading static certificate for {:?}: {:?}",
+                    static_cert.domains, e
+                ),
+            }
+        }
+    }
+}
+
+fn modified_time(static_cert: &settings::Certificate) -> Option<(SystemTime, SystemTime)> {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let (cert, key) = static_cert.files()?;
+
+    modified(cert).zip(modified(key))
+}
+
+fn load_static(static_cert: &settings::Certificate) -> Result<CertifiedKey> {
+    let (cert, key) = static_cert
+        .files()
+        .ok_or_else(|| eyre!("certificate has no static files"))?;
+
+    load_certkey(
+        File::open(cert).wrap_err_with(|| format!("failed opening {}", cert.display()))?,
+        File::open(key).wrap_err_with(|| format!("failed opening {}", key.display()))?,
+    )
+}
+
+/// Load a certificate chain and its private key from PEM encoded data. The chain is kept in the
+/// order found in the input and must start with the leaf certificate, followed by any
+/// intermediates. Private keys can be PKCS#8, PKCS#1 (RSA) or SEC1 (EC) encoded.
 fn load_certkey(cert: impl Read, key: impl Read) -> Result<CertifiedKey> {
-    let certs = rustls_pemfile::certs(&mut BufReader::new(cert))?
+    let certs = rustls_pemfile::certs(&mut BufReader::new(cert))
+        .wrap_err("failed reading certificate chain")?
         .into_iter()
         .map(rustls::Certificate)
-        .collect();
+        .collect::<Vec<_>>();
+
+    let leaf = certs
+        .first()
+        .ok_or_else(|| eyre!("no certificates found in certificate chain"))?;
+
+    let key = read_private_key(key)?;
+    let key = sign::any_supported_type(&key).map_err(|_| {
+        eyre!("unsupported private key type, expected an RSA, ECDSA or Ed25519 key")
+    })?;
 
-    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(key))?;
+    verify_key_matches(leaf, key.as_ref())?;
 
-    let certkey = CertifiedKey::new(
-        certs,
-        sign::any_supported_type(&rustls::PrivateKey(keys.swap_remove(0))).unwrap(),
-    );
+    Ok(CertifiedKey::new(certs, key))
+}
+
+fn read_private_key(key: impl Read) -> Result<rustls::PrivateKey> {
+    let mut reader = BufReader::new(key);
+
+    loop {
+        match rustls_pemfile::read_one(&mut reader).wrap_err("failed reading private key")? {
+            Some(
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key),
+            ) => break Ok(rustls::PrivateKey(key)),
+            Some(_) => {}
+            None => bail!("no PKCS#8, PKCS#1 or SEC1 private key found"),
+        }
+    }
+}
+
+/// Ensure the private key belongs to the leaf certificate, by signing a message with the key and
+/// verifying the signature with the certificate's public key.
+fn verify_key_matches(leaf: &rustls::Certificate, key: &dyn SigningKey) -> Result<()> {
+    const MESSAGE: &[u8] = b"charon certificate key check";
+
+    let signer = key
+        .choose_scheme(&[
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ED25519,
+            SignatureScheme::RSA_PKCS1_SHA256,
+        ])
+        .ok_or_else(|| eyre!("private key supports no known signature scheme"))?;
+
+    let algorithm = match signer.scheme() {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        _ => &webpki::RSA_PKCS1_2048_8192_SHA256,
+    };
+
+    let signature = signer
+        .sign(MESSAGE)
+        .map_err(|e| eyre!("failed signing with private key: {e}"))?;
+
+    webpki::EndEntityCert::try_from(leaf.0.as_slice())
+        .map_err(|e| eyre!("leaf certificate is invalid: {e:?}"))?
+        .verify_signature(algorithm, MESSAGE, &signature)
+        .map_err(|_| eyre!("private key doesn't match the leaf certificate"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn certkey(alg: &'static rcgen::SignatureAlgorithm) -> Arc<CertifiedKey> {
+        let mut params = rcgen::CertificateParams::new(vec!["charon.invalid".to_owned()]);
+        params.alg = alg;
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let key = sign::any_supported_type(&rustls::PrivateKey(cert.serialize_private_key_der()))
+            .unwrap();
+
+        Arc::new(CertifiedKey::new(
+            vec![rustls::Certificate(cert.serialize_der().unwrap())],
+            key,
+        ))
+    }
 
-    Ok(certkey)
+    const ECDSA: &[SignatureScheme] = &[SignatureScheme::ECDSA_NISTP256_SHA256];
+    const ED25519: &[SignatureScheme] = &[SignatureScheme::ED25519];
+    const ALL: &[SignatureScheme] = &[
+        SignatureScheme::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+    ];
+
+    fn map(names: &[&str]) -> HashMap<String, &'static str, RandomState> {
+        let mut map = HashMap::default();
+        for name in names {
+            map.insert((*name).to_owned(), "");
+        }
+        map
+    }
+
+    #[test]
+    fn lookup_exact() {
+        let map = map(&["example.com"]);
+
+        assert!(lookup(&map, "example.com").is_some());
+        assert!(lookup(&map, "www.example.com").is_none());
+        assert!(lookup(&map, "other.com").is_none());
+    }
+
+    #[test]
+    fn lookup_exact_before_wildcard() {
+        let mut map = HashMap::<_, _, RandomState>::default();
+        map.insert("www.example.com".to_owned(), "exact");
+        map.insert("*.example.com".to_owned(), "wildcard");
+
+        assert_eq!(Some(&"exact"), lookup(&map, "www.example.com"));
+        assert_eq!(Some(&"wildcard"), lookup(&map, "api.example.com"));
+    }
+
+    #[test]
+    fn lookup_wildcard_single_label() {
+        let map = map(&["*.example.com"]);
+
+        assert!(lookup(&map, "www.example.com").is_some());
+        assert!(lookup(&map, "example.com").is_none());
+        assert!(lookup(&map, "a.b.example.com").is_none());
+        assert!(lookup(&map, "wwwexample.com").is_none());
+        assert!(lookup(&map, "com").is_none());
+    }
+
+    #[test]
+    fn select_by_name() {
+        let exact = certkey(&rcgen::PKCS_ECDSA_P256_SHA256);
+        let wildcard = certkey(&rcgen::PKCS_ECDSA_P256_SHA256);
+        let mut resolver = SniResolver::default();
+        resolver.add("WWW.example.com", exact.clone());
+        resolver.add("*.example.com", wildcard.clone());
+
+        let selected = resolver.select(Some("www.EXAMPLE.com"), ECDSA).unwrap();
+        assert!(Arc::ptr_eq(&exact, &selected));
+
+        let selected = resolver.select(Some("api.example.com"), ECDSA).unwrap();
+        assert!(Arc::ptr_eq(&wildcard, &selected));
+
+        assert!(resolver.select(Some("a.b.example.com"), ECDSA).is_none());
+    }
+
+    #[test]
+    fn select_by_signature_scheme() {
+        let ecdsa = certkey(&rcgen::PKCS_ECDSA_P256_SHA256);
+        let ed25519 = certkey(&rcgen::PKCS_ED25519);
+        let mut resolver = SniResolver::default();
+        resolver.add("example.com", ed25519.clone());
+        resolver.add("example.com", ecdsa.clone());
+
+        let selected = resolver.select(Some("example.com"), ALL).unwrap();
+        assert!(Arc::ptr_eq(&ecdsa, &selected), "ECDSA is preferred");
+
+        let selected = resolver.select(Some("example.com"), ED25519).unwrap();
+        assert!(Arc::ptr_eq(&ed25519, &selected));
+
+        assert!(resolver
+            .select(Some("example.com"), &[SignatureScheme::RSA_PSS_SHA256])
+            .is_none());
+    }
+
+    #[test]
+    fn select_placeholder_and_fallback() {
+        let placeholder = certkey(&rcgen::PKCS_ECDSA_P256_SHA256);
+        let fallback = certkey(&rcgen::PKCS_ECDSA_P256_SHA256);
+        let mut resolver = SniResolver {
+            fallback: Some(fallback.clone()),
+            ..SniResolver::default()
+        };
+        resolver
+            .placeholders
+            .insert("new.example.com".to_owned(), placeholder.clone());
+
+        let selected = resolver.select(Some("new.example.com"), ECDSA).unwrap();
+        assert!(Arc::ptr_eq(&placeholder, &selected));
+
+        let selected = resolver.select(Some("unknown.example.com"), ECDSA).unwrap();
+        assert!(Arc::ptr_eq(&fallback, &selected));
+
+        let selected = resolver.select(None, ECDSA).unwrap();
+        assert!(Arc::ptr_eq(&fallback, &selected));
+    }
+
+    #[test]
+    fn select_reports_unknown_names() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let mut resolver = SniResolver::default();
+        resolver.add("example.com", certkey(&rcgen::PKCS_ECDSA_P256_SHA256));
+        resolver.set_on_demand(tx);
+
+        resolver.select(Some("example.com"), ECDSA);
+        resolver.select(None, ECDSA);
+        assert!(rx.try_recv().is_err());
+
+        resolver.select(Some("New.Example.org"), ECDSA);
+        assert_eq!("new.example.org", rx.try_recv().unwrap());
+    }
 }
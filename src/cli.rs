@@ -0,0 +1,177 @@
+//! Command line interface for managing the ACME account and its certificates.
+
+use std::env;
+
+use eyre::{bail, eyre, Result};
+use log::{info, warn};
+
+use crate::{
+    acme::{Acme, AcmeHandle, RevokeReason},
+    settings::Settings,
+};
+
+const USAGE: &str = "\
+Usage: charon [COMMAND]
+
+Without a command, the proxy is started.
+
+Commands:
+  revoke <DOMAIN> [REASON]  Revoke all certificates of the group that contains DOMAIN. REASON is
+                            one of unspecified (default), key-compromise, affiliation-changed,
+                            superseded or cessation-of-operation.
+  rotate-key                Replace the ACME account key with a new one.
+  update-email <EMAIL>      Change the account's contact address.
+  deactivate                Permanently deactivate the ACME account.
+  help                      Print this message.";
+
+pub enum Command {
+    Serve,
+    Help,
+    Revoke {
+        domain: String,
+        reason: RevokeReason,
+    },
+    RotateKey,
+    UpdateEmail(String),
+    Deactivate,
+}
+
+pub fn parse() -> Result<Command> {
+    parse_args(env::args().skip(1))
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Command> {
+    let Some(command) = args.next() else {
+        return Ok(Command::Serve);
+    };
+
+    let command = match command.as_str() {
+        "help" | "-h" | "--help" => Command::Help,
+        "revoke" => Command::Revoke {
+            domain: args
+                .next()
+                .ok_or_else(|| eyre!("missing domain to revoke"))?
+                .to_lowercase(),
+            reason: args
+                .next()
+                .as_deref()
+                .map_or(Ok(RevokeReason::default()), str::parse)?,
+        },
+        "rotate-key" => Command::RotateKey,
+        "update-email" => {
+            Command::UpdateEmail(args.next().ok_or_else(|| eyre!("missing email address"))?)
+        }
+        "deactivate" => Command::Deactivate,
+        _ => bail!("unknown command `{command}`\n\n{USAGE}"),
+    };
+
+    if let Some(arg) = args.next() {
+        bail!("unexpected argument `{arg}`\n\n{USAGE}");
+    }
+
+    Ok(command)
+}
+
+pub fn print_usage() {
+    println!("{USAGE}");
+}
+
+/// Run a management command against the account from the settings.
+pub async fn run(command: Command, settings: &Settings, acme: &AcmeHandle) -> Result<()> {
+    match command {
+        Command::Serve | Command::Help => {}
+        Command::Revoke { domain, reason } => {
+            let group = settings
+                .acme_groups()
+                .into_iter()
+                .find(|group| group.domains.contains(&domain))
+                .ok_or_else(|| eyre!("no ACME certificate is configured for {domain}"))?;
+
+            let mut revoked = false;
+            for key_type in group.key_types {
+                let domains = group.domains.clone();
+                if acme
+                    .run(move |acme| acme.revoke(&domains, key_type, reason))
+                    .await?
+                {
+                    info!("revoked {} certificate for {domain}", key_type.as_str());
+                    revoked = true;
+                }
+            }
+
+            if !revoked {
+                bail!("no certificate stored for {domain}");
+            }
+
+            warn!("restart the proxy to order replacement certificates");
+        }
+        Command::RotateKey => {
+            acme.run(Acme::rotate_key).await?;
+            info!("rotated the account key");
+        }
+        Command::UpdateEmail(email) => {
+            let new_email = email.clone();
+            acme.run(move |acme| acme.update_email(&new_email)).await?;
+            info!("updated the contact address, set `acme.email` to {email} in the settings now");
+        }
+        Command::Deactivate => {
+            acme.run(Acme::deactivate).await?;
+            info!("deactivated the account");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command> {
+        parse_args(args.iter().map(|&arg| arg.to_owned()))
+    }
+
+    #[test]
+    fn serve_without_command() {
+        assert!(matches!(parse(&[]), Ok(Command::Serve)));
+        assert!(matches!(parse(&["--help"]), Ok(Command::Help)));
+    }
+
+    #[test]
+    fn revoke_with_reason() {
+        assert!(matches!(
+            parse(&["revoke", "Sample.COM"]),
+            Ok(Command::Revoke {
+                domain,
+                reason: RevokeReason::Unspecified,
+            }) if domain == "sample.com"
+        ));
+        assert!(matches!(
+            parse(&["revoke", "sample.com", "key-compromise"]),
+            Ok(Command::Revoke {
+                reason: RevokeReason::KeyCompromise,
+                ..
+            })
+        ));
+        assert!(parse(&["revoke"]).is_err());
+        assert!(parse(&["revoke", "sample.com", "lost"]).is_err());
+    }
+
+    #[test]
+    fn account_commands() {
+        assert!(matches!(parse(&["rotate-key"]), Ok(Command::RotateKey)));
+        assert!(matches!(parse(&["deactivate"]), Ok(Command::Deactivate)));
+        assert!(matches!(
+            parse(&["update-email", "new@sample.com"]),
+            Ok(Command::UpdateEmail(email)) if email == "new@sample.com"
+        ));
+        assert!(parse(&["update-email"]).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_and_extra_arguments() {
+        assert!(parse(&["renew"]).is_err());
+        assert!(parse(&["rotate-key", "now"]).is_err());
+        assert!(parse(&["revoke", "sample.com", "superseded", "extra"]).is_err());
+    }
+}
@@ -0,0 +1,472 @@
+//! Forwarding of raw TCP streams and UDP datagrams to upstreams, for protocols other than HTTP.
+
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use ahash::RandomState;
+use eyre::{eyre, Result};
+use futures_util::future::try_join;
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::Semaphore,
+    time::Instant,
+};
+
+use crate::{listeners::Listeners, proxy_protocol, settings, shutdown::Shutdown};
+
+const BUF_SIZE: usize = 16 * 1024;
+/// Largest possible UDP payload.
+const MAX_DATAGRAM: usize = 65_535;
+
+/// Copy data in both directions until both sides closed their end, returning the amount of bytes
+/// the client wrote and received. With an idle timeout, the connection is closed once neither
+/// side sent anything for that long, returning the amounts copied until then.
+pub async fn copy<C, S>(
+    client: C,
+    server: S,
+    idle_timeout: Option<Duration>,
+) -> io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite,
+    S: AsyncRead + AsyncWrite,
+{
+    let activity = Mutex::new(Instant::now());
+    let from_client = AtomicU64::new(0);
+    let to_client = AtomicU64::new(0);
+
+    let (mut client_rx, mut client_tx) = io::split(client);
+    let (mut server_rx, mut server_tx) = io::split(server);
+
+    let transfer = try_join(
+        copy_half(&mut client_rx, &mut server_tx, &activity, &from_client),
+        copy_half(&mut server_rx, &mut client_tx, &activity, &to_client),
+    );
+
+    let Some(idle_timeout) = idle_timeout else {
+        return transfer.await;
+    };
+
+    tokio::select! {
+        amounts = transfer => amounts,
+        () = idle(&activity, idle_timeout) => {
+            debug!("closing connection that was idle for {:?}", idle_timeout);
+            Ok((from_client.into_inner(), to_client.into_inner()))
+        }
+    }
+}
+
+async fn copy_half<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    activity: &Mutex<Instant>,
+    copied: &AtomicU64,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; BUF_SIZE];
+
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            writer.shutdown().await?;
+            return Ok(copied.load(Ordering::Relaxed));
+        }
+
+        writer.write_all(&buf[..read]).await?;
+        copied.fetch_add(read as u64, Ordering::Relaxed);
+        *activity.lock() = Instant::now();
+    }
+}
+
+/// Complete once there was no activity for the given timeout.
+async fn idle(activity: &Mutex<Instant>, timeout: Duration) {
+    loop {
+        let deadline = *activity.lock() + timeout;
+        if deadline <= Instant::now() {
+            return;
+        }
+
+        tokio::time::sleep_until(deadline).await;
+    }
+}
+
+/// Bytes that all clients of a listener sent and received.
+#[derive(Default)]
+struct Totals {
+    from_clients: AtomicU64,
+    to_clients: AtomicU64,
+}
+
+impl Totals {
+    fn add(&self, from_client: u64, to_client: u64) -> (u64, u64) {
+        (
+            self.from_clients.fetch_add(from_client, Ordering::Relaxed) + from_client,
+            self.to_clients.fetch_add(to_client, Ordering::Relaxed) + to_client,
+        )
+    }
+}
+
+pub struct TcpProxy {
+    listener: TcpListener,
+    settings: Arc<settings::Tcp>,
+    proxy_protocol: Arc<settings::ProxyProtocol>,
+    limit: Arc<Semaphore>,
+    totals: Arc<Totals>,
+    shutdown: Shutdown,
+}
+
+impl TcpProxy {
+    pub fn bind(
+        settings: settings::Tcp,
+        listeners: &Listeners,
+        proxy_protocol: Arc<settings::ProxyProtocol>,
+        shutdown: Shutdown,
+    ) -> Result<Self> {
+        Ok(Self {
+            listener: listeners.tcp(settings.listen)?,
+            limit: Arc::new(Semaphore::new(settings.max_connections)),
+            settings: Arc::new(settings),
+            proxy_protocol,
+            totals: Arc::default(),
+            shutdown,
+        })
+    }
+
+    pub async fn run(self) {
+        info!(
+            "listening on {} for TCP to {}",
+            self.settings.listen, self.settings.upstream
+        );
+
+        let signaled = self.shutdown.signaled();
+        tokio::pin!(signaled);
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = self.listener.accept() => accepted,
+                () = &mut signaled => break,
+            };
+
+            let (stream, remote_addr) = match accepted {
+                Ok(conn) => conn,
+                Err(e) => {
+                    // Mostly caused by running out of file descriptors, which takes a moment to
+                    // resolve, same as hyper does it.
+                    warn!("failed accepting TCP connection: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            let Ok(permit) = self.limit.clone().try_acquire_owned() else {
+                warn!(
+                    "closing TCP connection from {}, the limit of {} connections on {} is reached",
+                    remote_addr, self.settings.max_connections, self.settings.listen
+                );
+                continue;
+            };
+
+            let settings = self.settings.clone();
+            let proxy_protocol = self.proxy_protocol.clone();
+            let totals = self.totals.clone();
+            let active = self.shutdown.track();
+
+            tokio::spawn(async move {
+                if let Err(e) =
+                    forward_tcp(stream, remote_addr, &settings, &proxy_protocol, &totals).await
+                {
+                    warn!(
+                        "TCP connection from {} to {} failed: {}",
+                        remote_addr, settings.upstream, e
+                    );
+                }
+
+                drop(permit);
+                drop(active);
+            });
+        }
+
+        info!("stopped listening on {} for TCP", self.settings.listen);
+    }
+}
+
+async fn forward_tcp(
+    mut stream: TcpStream,
+    mut remote_addr: SocketAddr,
+    settings: &settings::Tcp,
+    proxy_protocol: &settings::ProxyProtocol,
+    totals: &Totals,
+) -> io::Result<()> {
+    if settings.proxy_protocol && proxy_protocol.trusts(remote_addr.ip()) {
+        let header = tokio::time::timeout(
+            proxy_protocol::HEADER_TIMEOUT,
+            proxy_protocol::read_header(&mut stream),
+        )
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no PROXY protocol header"))??;
+        remote_addr = header.unwrap_or(remote_addr);
+    }
+
+    let mut server = TcpStream::connect(&settings.upstream).await?;
+    if let Some(&version) = proxy_protocol.upstreams.get(&settings.upstream) {
+        let header = proxy_protocol::header(version, remote_addr, stream.local_addr()?);
+        server.write_all(&header).await?;
+    }
+
+    let (from_client, to_client) = copy(
+        stream,
+        server,
+        Some(Duration::from_secs(settings.idle_timeout)),
+    )
+    .await?;
+
+    let (total_from, total_to) = totals.add(from_client, to_client);
+    info!(
+        "TCP connection from {} to {} closed, client wrote {} bytes, received {} bytes ({} and {} \
+         in total)",
+        remote_addr, settings.upstream, from_client, to_client, total_from, total_to
+    );
+
+    Ok(())
+}
+
+pub struct UdpProxy {
+    socket: Arc<UdpSocket>,
+    settings: Arc<settings::Udp>,
+    /// Address of the upstream, resolved once so receiving datagrams never waits for DNS.
+    upstream: SocketAddr,
+    sessions: Arc<Mutex<HashMap<SocketAddr, Arc<Session>, RandomState>>>,
+    totals: Arc<Totals>,
+    shutdown: Shutdown,
+}
+
+/// Datagrams of a single client, which are forwarded through their own upstream socket.
+struct Session {
+    upstream: UdpSocket,
+    activity: Mutex<Instant>,
+    from_client: AtomicU64,
+    to_client: AtomicU64,
+}
+
+impl UdpProxy {
+    pub fn bind(
+        settings: settings::Udp,
+        listeners: &Listeners,
+        shutdown: Shutdown,
+    ) -> Result<Self> {
+        let upstream = settings
+            .upstream
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| eyre!("{} doesn't resolve to any address", settings.upstream))?;
+
+        Ok(Self {
+            socket: Arc::new(listeners.udp(settings.listen)?),
+            settings: Arc::new(settings),
+            upstream,
+            sessions: Arc::default(),
+            totals: Arc::default(),
+            shutdown,
+        })
+    }
+
+    pub async fn run(self) {
+        info!(
+            "listening on {} for UDP to {}",
+            self.settings.listen, self.settings.upstream
+        );
+
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let signaled = self.shutdown.signaled();
+        tokio::pin!(signaled);
+
+        // Sessions aren't waited for on shutdown, as there is no way to tell whether the
+        // application on top of UDP is done.
+        loop {
+            let received = tokio::select! {
+                received = self.socket.recv_from(&mut buf) => received,
+                () = &mut signaled => break,
+            };
+
+            let (len, client) = match received {
+                Ok(datagram) => datagram,
+                Err(e) => {
+                    // Errors of previous sends can be reported here, which don't affect others.
+                    debug!("failed receiving UDP datagram: {}", e);
+                    continue;
+                }
+            };
+
+            let session = self.sessions.lock().get(&client).cloned();
+            let session = match session {
+                Some(session) => session,
+                None => match self.open(client).await {
+                    Ok(Some(session)) => session,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        warn!(
+                            "failed opening UDP session from {} to {}: {:?}",
+                            client, self.settings.upstream, e
+                        );
+                        continue;
+                    }
+                },
+            };
+
+            *session.activity.lock() = Instant::now();
+            session.from_client.fetch_add(len as u64, Ordering::Relaxed);
+
+            if let Err(e) = session.upstream.send(&buf[..len]).await {
+                debug!("failed forwarding UDP datagram from {}: {}", client, e);
+            }
+        }
+
+        info!("stopped listening on {} for UDP", self.settings.listen);
+    }
+
+    /// Open a new session for the client, unless the limit of sessions is reached.
+    async fn open(&self, client: SocketAddr) -> Result<Option<Arc<Session>>> {
+        if self.sessions.lock().len() >= self.settings.max_sessions {
+            warn!(
+                "dropping UDP datagram from {}, the limit of {} sessions on {} is reached",
+                client, self.settings.max_sessions, self.settings.listen
+            );
+            return Ok(None);
+        }
+
+        let local: SocketAddr = if self.upstream.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0; 16], 0).into()
+        };
+
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(self.upstream).await?;
+
+        let session = Arc::new(Session {
+            upstream: socket,
+            activity: Mutex::new(Instant::now()),
+            from_client: AtomicU64::new(0),
+            to_client: AtomicU64::new(0),
+        });
+        self.sessions.lock().insert(client, session.clone());
+
+        tokio::spawn(reply(
+            self.socket.clone(),
+            self.settings.clone(),
+            self.sessions.clone(),
+            self.totals.clone(),
+            client,
+            session.clone(),
+        ));
+
+        Ok(Some(session))
+    }
+}
+
+/// Send the upstream's datagrams back to the client, until the session is idle for too long.
+async fn reply(
+    socket: Arc<UdpSocket>,
+    settings: Arc<settings::Udp>,
+    sessions: Arc<Mutex<HashMap<SocketAddr, Arc<Session>, RandomState>>>,
+    totals: Arc<Totals>,
+    client: SocketAddr,
+    session: Arc<Session>,
+) {
+    let idle_timeout = Duration::from_secs(settings.idle_timeout);
+    let mut buf = vec![0; MAX_DATAGRAM];
+
+    loop {
+        let deadline = *session.activity.lock() + idle_timeout;
+
+        match tokio::time::timeout_at(deadline, session.upstream.recv(&mut buf)).await {
+            Ok(Ok(len)) => {
+                *session.activity.lock() = Instant::now();
+                session.to_client.fetch_add(len as u64, Ordering::Relaxed);
+
+                if let Err(e) = socket.send_to(&buf[..len], client).await {
+                    debug!("failed sending UDP datagram to {}: {}", client, e);
+                }
+            }
+            Ok(Err(e)) => {
+                warn!(
+                    "UDP session from {} to {} failed: {}",
+                    client, settings.upstream, e
+                );
+                break;
+            }
+            Err(_) => {
+                // Datagrams from the client might have arrived meanwhile.
+                if *session.activity.lock() + idle_timeout <= Instant::now() {
+                    break;
+                }
+            }
+        }
+    }
+
+    sessions.lock().remove(&client);
+
+    let from_client = session.from_client.load(Ordering::Relaxed);
+    let to_client = session.to_client.load(Ordering::Relaxed);
+    let (total_from, total_to) = totals.add(from_client, to_client);
+    info!(
+        "UDP session from {} to {} closed, client wrote {} bytes, received {} bytes ({} and {} in \
+         total)",
+        client, settings.upstream, from_client, to_client, total_from, total_to
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn copy_until_both_sides_closed() {
+        let (client, mut client_peer) = io::duplex(64);
+        let (server, mut server_peer) = io::duplex(64);
+
+        let transfer = tokio::spawn(copy(client, server, None));
+
+        client_peer.write_all(b"request").await.unwrap();
+        client_peer.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        server_peer.read_to_end(&mut request).await.unwrap();
+
+        server_peer.write_all(b"reply").await.unwrap();
+        server_peer.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        client_peer.read_to_end(&mut reply).await.unwrap();
+
+        assert_eq!(b"request".as_slice(), request);
+        assert_eq!(b"reply".as_slice(), reply);
+        assert_eq!((7, 5), transfer.await.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn copy_keeps_amounts_when_idle() {
+        let (client, mut client_peer) = io::duplex(64);
+        let (server, mut server_peer) = io::duplex(64);
+
+        let transfer = tokio::spawn(copy(client, server, Some(Duration::from_millis(100))));
+
+        client_peer.write_all(b"request").await.unwrap();
+        let mut buf = [0; 7];
+        server_peer.read_exact(&mut buf).await.unwrap();
+        server_peer.write_all(b"hi").await.unwrap();
+        client_peer.read_exact(&mut buf[..2]).await.unwrap();
+
+        assert_eq!((7, 2), transfer.await.unwrap().unwrap());
+    }
+}
@@ -0,0 +1,216 @@
+//! Handoff of the listening sockets to a new process over a Unix socket, to upgrade charon without
+//! refusing any connections. The new process connects to the socket of the running one, receives
+//! its listening sockets and tells it to shut down once it's serving them itself.
+
+use std::{
+    fs::{self, Permissions},
+    io::{self, IoSlice, IoSliceMut, Write},
+    net::{TcpListener, UdpSocket},
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::{fs::PermissionsExt, net::UnixStream},
+    },
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use eyre::{bail, Result, WrapErr};
+use log::{info, warn};
+use rustix::net::{
+    sockopt, RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags, SendAncillaryBuffer,
+    SendAncillaryMessage, SendFlags, SocketType,
+};
+use tokio::{io::AsyncReadExt, net::UnixListener};
+
+use crate::{
+    listeners::{Listeners, Socket},
+    settings,
+    shutdown::Shutdown,
+};
+
+/// Sent by the new process once it serves the sockets.
+const READY: u8 = 1;
+/// Most file descriptors that can be passed in a single message on Linux.
+const MAX_SOCKETS: usize = 253;
+/// Time the previous process has to send its sockets, in case it's busy with another handoff.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Handoff {
+    path: PathBuf,
+    listeners: Arc<Listeners>,
+    shutdown: Shutdown,
+    /// Connection to the previous process, which waits for the signal to shut down.
+    predecessor: Option<UnixStream>,
+}
+
+impl Handoff {
+    /// Take over the listening sockets of the process that is running on the handoff socket, if
+    /// there is one.
+    pub fn connect(
+        settings: settings::Handoff,
+        listeners: Arc<Listeners>,
+        shutdown: Shutdown,
+    ) -> Result<Self> {
+        let predecessor = match UnixStream::connect(&settings.socket) {
+            Ok(stream) => {
+                let sockets = receive(&stream).wrap_err("failed receiving listening sockets")?;
+                info!(
+                    "took over {} sockets from the previous process",
+                    sockets.len()
+                );
+                listeners.inherit(sockets);
+                Some(stream)
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                None
+            }
+            Err(e) => {
+                return Err(e).wrap_err_with(|| {
+                    format!(
+                        "failed connecting to handoff socket {}",
+                        settings.socket.display()
+                    )
+                })
+            }
+        };
+
+        Ok(Self {
+            path: settings.socket,
+            listeners,
+            shutdown,
+            predecessor,
+        })
+    }
+
+    /// Whether the sockets of a previous process were taken over.
+    pub const fn took_over(&self) -> bool {
+        self.predecessor.is_some()
+    }
+
+    /// Tell the previous process to shut down, then wait for the next one to take over.
+    pub async fn run(self) {
+        if let Err(e) = self.serve().await {
+            warn!("socket handoff stopped: {:?}", e);
+        }
+    }
+
+    async fn serve(mut self) -> Result<()> {
+        if let Some(mut predecessor) = self.predecessor.take() {
+            predecessor.write_all(&[READY])?;
+        }
+
+        // The previous process might still listen on the path, but it's done with it.
+        match fs::remove_file(&self.path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let listener = UnixListener::bind(&self.path)
+            .wrap_err_with(|| format!("failed binding handoff socket {}", self.path.display()))?;
+        fs::set_permissions(&self.path, Permissions::from_mode(0o600))?;
+
+        let signaled = self.shutdown.signaled();
+        tokio::pin!(signaled);
+
+        loop {
+            let (stream, _) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                () = &mut signaled => return Ok(()),
+            };
+
+            if let Err(e) = self.hand_over(stream).await {
+                warn!("failed handing over listening sockets: {:?}", e);
+            }
+        }
+    }
+
+    async fn hand_over(&self, stream: tokio::net::UnixStream) -> Result<()> {
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        send(&stream, &self.listeners.sockets()?)?;
+        stream.set_nonblocking(true)?;
+
+        // Without the signal, the new process failed to start and this one has to keep serving.
+        let mut stream = tokio::net::UnixStream::from_std(stream)?;
+        if stream.read_u8().await.ok() == Some(READY) {
+            info!("new process took over the listening sockets, shutting down");
+            self.shutdown.trigger();
+        } else {
+            warn!("new process closed the handoff before serving the listening sockets");
+        }
+
+        Ok(())
+    }
+}
+
+fn send(stream: &UnixStream, sockets: &[Socket]) -> Result<()> {
+    if sockets.len() > MAX_SOCKETS {
+        bail!("can't hand over more than {MAX_SOCKETS} sockets");
+    }
+
+    let fds = sockets
+        .iter()
+        .map(|socket| match socket {
+            Socket::Tcp(listener) => listener.as_fd(),
+            Socket::Udp(socket) => socket.as_fd(),
+        })
+        .collect::<Vec<BorrowedFd<'_>>>();
+
+    let mut space = vec![0; rustix::cmsg_space!(ScmRights(fds.len()))];
+    let mut control = SendAncillaryBuffer::new(&mut space);
+    control.push(SendAncillaryMessage::ScmRights(&fds));
+
+    // The count is only sent because at least one byte of data has to accompany the sockets.
+    #[allow(clippy::cast_possible_truncation)]
+    let count = [sockets.len() as u8];
+    rustix::net::sendmsg(
+        stream,
+        &[IoSlice::new(&count)],
+        &mut control,
+        SendFlags::empty(),
+    )?;
+
+    Ok(())
+}
+
+fn receive(stream: &UnixStream) -> Result<Vec<Socket>> {
+    stream.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+
+    let mut space = vec![0; rustix::cmsg_space!(ScmRights(MAX_SOCKETS))];
+    let mut control = RecvAncillaryBuffer::new(&mut space);
+    let mut count = [0];
+
+    let received = rustix::net::recvmsg(
+        stream,
+        &mut [IoSliceMut::new(&mut count)],
+        &mut control,
+        RecvFlags::CMSG_CLOEXEC,
+    )?;
+    if received.bytes == 0 {
+        bail!("previous process closed the handoff without sending the sockets");
+    }
+
+    let mut sockets = Vec::with_capacity(usize::from(count[0]));
+    for message in control.drain() {
+        let RecvAncillaryMessage::ScmRights(fds) = message else {
+            continue;
+        };
+
+        for fd in fds {
+            match sockopt::get_socket_type(&fd)? {
+                SocketType::STREAM => sockets.push(Socket::Tcp(TcpListener::from(fd))),
+                SocketType::DGRAM => sockets.push(Socket::Udp(UdpSocket::from(fd))),
+                _ => warn!("ignoring passed socket of unknown type"),
+            }
+        }
+    }
+
+    Ok(sockets)
+}
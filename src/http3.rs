@@ -0,0 +1,434 @@
+//! HTTP/3 over QUIC, which serves the same routes as HTTPS with the certificates of the shared
+//! resolver. QUIC comes with a newer version of rustls and the `http` crate than the rest of
+//! charon, so certificates, requests and responses are converted between both versions.
+
+use std::{
+    fmt::{self, Debug},
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::Arc,
+    time::Duration,
+};
+
+use eyre::{Result, WrapErr};
+use futures_util::future::poll_fn;
+use h3::server::{RequestResolver, RequestStream};
+use hyper::{
+    body::{Buf, Bytes, HttpBody},
+    http::header::{HeaderName, HeaderValue, CONNECTION, HOST, TRANSFER_ENCODING, UPGRADE},
+    Body, Request, Response,
+};
+use log::{debug, info, warn};
+use quinn::{
+    crypto::rustls::{HandshakeData, QuicServerConfig},
+    rustls::{self as quic_rustls, pki_types::CertificateDer},
+};
+use tower::Service;
+
+use crate::{
+    cert::{Resolver, SniResolver},
+    listeners::Listeners,
+    services::MakeSvc,
+    settings,
+    shutdown::Shutdown,
+    tls::ConnInfo,
+};
+
+/// Protocol identifier of HTTP/3 in ALPN.
+pub const ALPN: &str = "h3";
+/// Interval to check whether the previous process released the UDP socket after an upgrade.
+const BIND_RETRY: Duration = Duration::from_secs(1);
+
+/// Response headers that are specific to the upstream connection, which HTTP/3 doesn't allow.
+static CONNECTION_HEADERS: [HeaderName; 5] = [
+    CONNECTION,
+    TRANSFER_ENCODING,
+    UPGRADE,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+];
+
+/// HTTP/3 server, with a UDP socket of its own. Unlike the other listening sockets, it's never
+/// handed over to the next process: QUIC connections live in the process that owns them, so with
+/// both processes reading from the same socket, datagrams of existing connections would reach the
+/// new one, which can only answer them with stateless resets. Instead, the previous process keeps
+/// serving its HTTP/3 connections until it's drained, and the next one binds the socket once it's
+/// released. Meanwhile, new HTTP/3 connections are refused and clients fall back to HTTPS.
+pub struct Http3Server {
+    listen: SocketAddr,
+    socket: Option<UdpSocket>,
+    config: quinn::ServerConfig,
+    make_svc: MakeSvc,
+    shutdown: Shutdown,
+}
+
+impl Http3Server {
+    /// Bind the UDP socket. If `took_over` is set, because this process took over the sockets of
+    /// a previous one, it's fine for the socket to be in use, as the previous process releases it
+    /// once it stopped.
+    pub fn bind(
+        settings: &settings::Http3,
+        listeners: &Listeners,
+        resolver: Arc<Resolver<SniResolver>>,
+        make_svc: MakeSvc,
+        shutdown: Shutdown,
+        took_over: bool,
+    ) -> Result<Self> {
+        let mut tls = quic_rustls::ServerConfig::builder_with_provider(Arc::new(
+            quic_rustls::crypto::ring::default_provider(),
+        ))
+        .with_protocol_versions(&[&quic_rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(QuicResolver(resolver)));
+        tls.alpn_protocols = vec![ALPN.as_bytes().to_vec()];
+
+        let socket = match listeners.udp_unshared(settings.listen) {
+            Ok(socket) => Some(socket),
+            Err(e) if took_over && e.kind() == io::ErrorKind::AddrInUse => None,
+            Err(e) => {
+                return Err(e).wrap_err_with(|| {
+                    format!("failed binding HTTP/3 listener on {}", settings.listen)
+                })
+            }
+        };
+
+        Ok(Self {
+            listen: settings.listen,
+            socket,
+            config: quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?)),
+            make_svc,
+            shutdown,
+        })
+    }
+
+    pub async fn run(mut self) {
+        let Some(socket) = self.socket().await else {
+            return;
+        };
+
+        let endpoint = socket.set_nonblocking(true).and_then(|()| {
+            quinn::Endpoint::new(
+                quinn::EndpointConfig::default(),
+                Some(self.config.clone()),
+                socket,
+                Arc::new(quinn::TokioRuntime),
+            )
+        });
+        let endpoint = match endpoint {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                warn!("failed starting HTTP/3 listener on {}: {}", self.listen, e);
+                return;
+            }
+        };
+        let local_addr = endpoint.local_addr().unwrap_or(self.listen);
+        info!("listening on {} for HTTP/3", local_addr);
+
+        let signaled = self.shutdown.signaled();
+        tokio::pin!(signaled);
+
+        loop {
+            let incoming = tokio::select! {
+                incoming = endpoint.accept() => incoming,
+                () = &mut signaled => break,
+            };
+            let Some(incoming) = incoming else {
+                break;
+            };
+
+            let make_svc = self.make_svc.clone();
+            let shutdown = self.shutdown.clone();
+            let active = self.shutdown.track();
+
+            tokio::spawn(async move {
+                let remote_addr = incoming.remote_address();
+                if let Err(e) = serve(incoming, local_addr, &make_svc, &shutdown).await {
+                    debug!("HTTP/3 connection from {} failed: {}", remote_addr, e);
+                }
+
+                drop(active);
+            });
+        }
+
+        // Refuse new connections, while the existing ones finish their requests.
+        endpoint.set_server_config(None);
+        info!("stopped listening on {} for HTTP/3", local_addr);
+    }
+
+    /// The bound socket, or once the previous process released it, a newly bound one. Nothing is
+    /// returned if the shutdown is triggered first.
+    async fn socket(&mut self) -> Option<UdpSocket> {
+        if let Some(socket) = self.socket.take() {
+            return Some(socket);
+        }
+
+        info!(
+            "waiting for the previous process to release {} for HTTP/3",
+            self.listen
+        );
+
+        let signaled = self.shutdown.signaled();
+        tokio::pin!(signaled);
+
+        loop {
+            tokio::select! {
+                () = tokio::time::sleep(BIND_RETRY) => {}
+                () = &mut signaled => return None,
+            }
+
+            match UdpSocket::bind(self.listen) {
+                Ok(socket) => return Some(socket),
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => {}
+                Err(e) => {
+                    warn!("failed binding HTTP/3 listener on {}: {}", self.listen, e);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+async fn serve(
+    incoming: quinn::Incoming,
+    local_addr: SocketAddr,
+    make_svc: &MakeSvc,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let remote_addr = incoming.remote_address();
+    let conn = incoming.await?;
+
+    let info = Arc::new(ConnInfo {
+        server_name: conn
+            .handshake_data()
+            .and_then(|data| data.downcast::<HandshakeData>().ok())
+            .and_then(|data| data.server_name)
+            .map(|name| name.to_ascii_lowercase()),
+        alpn_protocol: Some(ALPN.to_owned()),
+        version: "TLSv1.3",
+        client_cert: None,
+    });
+    let local_addr = SocketAddr::new(
+        conn.local_ip().unwrap_or_else(|| local_addr.ip()),
+        local_addr.port(),
+    );
+
+    debug!(
+        "{} connected with QUIC for {}",
+        remote_addr,
+        info.server_name.as_deref().unwrap_or("-")
+    );
+
+    let mut conn = h3::server::Connection::new(h3_quinn::Connection::new(conn)).await?;
+    let signaled = shutdown.signaled();
+    tokio::pin!(signaled);
+    let mut draining = false;
+
+    loop {
+        let resolver = tokio::select! {
+            resolver = conn.accept() => resolver?,
+            () = &mut signaled, if !draining => {
+                // Tell the client to stop sending requests, while the current ones finish.
+                draining = true;
+                conn.shutdown(0).await?;
+                continue;
+            }
+        };
+        let Some(resolver) = resolver else {
+            return Ok(());
+        };
+
+        let svc = make_svc.service(info.clone(), remote_addr, local_addr);
+        tokio::spawn(async move {
+            if let Err(e) = handle(resolver, svc).await {
+                debug!("HTTP/3 request from {} failed: {}", remote_addr, e);
+            }
+        });
+    }
+}
+
+async fn handle<S>(resolver: RequestResolver<h3_quinn::Connection, Bytes>, mut svc: S) -> Result<()>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = hyper::Error>,
+{
+    let (req, stream) = resolver.resolve_request().await?;
+    let (mut send, recv) = stream.split();
+
+    // Tunnels rely on HTTP/1.1 upgrades, which don't exist in HTTP/3.
+    if req.method() == http::Method::CONNECT {
+        let resp = http::Response::builder()
+            .status(http::StatusCode::NOT_IMPLEMENTED)
+            .body(())?;
+        send.send_response(resp).await?;
+        send.finish().await?;
+        return Ok(());
+    }
+
+    let body = receive_body(recv).await?;
+
+    poll_fn(|cx| svc.poll_ready(cx)).await?;
+    let resp = svc.call(convert_request(req, body)?).await?;
+
+    let (parts, mut body) = resp.into_parts();
+    let mut resp = http::Response::new(());
+    *resp.status_mut() = http::StatusCode::from_u16(parts.status.as_u16())?;
+    for (name, value) in &parts.headers {
+        if !CONNECTION_HEADERS.contains(name) {
+            resp.headers_mut().append(
+                http::HeaderName::from_bytes(name.as_str().as_bytes())?,
+                http::HeaderValue::from_bytes(value.as_bytes())?,
+            );
+        }
+    }
+
+    send.send_response(resp).await?;
+    while let Some(data) = body.data().await {
+        send.send_data(data?).await?;
+    }
+    send.finish().await?;
+
+    Ok(())
+}
+
+/// Body that streams the request data. Requests without any, like most GET requests, get an
+/// empty body, so upstreams don't receive a chunked one.
+async fn receive_body(mut recv: RequestStream<h3_quinn::RecvStream, Bytes>) -> Result<Body> {
+    let Some(mut first) = recv.recv_data().await? else {
+        return Ok(Body::empty());
+    };
+    let (mut body_tx, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let mut chunk = Some(first.copy_to_bytes(first.remaining()));
+
+        while let Some(data) = chunk.take() {
+            if body_tx.send_data(data).await.is_err() {
+                return;
+            }
+
+            match recv.recv_data().await {
+                Ok(next) => chunk = next.map(|mut next| next.copy_to_bytes(next.remaining())),
+                Err(e) => {
+                    debug!("failed receiving HTTP/3 request body: {}", e);
+                    body_tx.abort();
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(body)
+}
+
+/// Convert the request into the form that HTTP/1.1 requests arrive in, as upstreams are spoken to
+/// over HTTP/1.1. The URI only keeps the path and the authority becomes the `Host` header.
+fn convert_request(req: http::Request<()>, body: Body) -> Result<Request<Body>> {
+    let (parts, ()) = req.into_parts();
+
+    let mut converted = Request::new(body);
+    *converted.method_mut() = hyper::Method::from_bytes(parts.method.as_str().as_bytes())?;
+    *converted.uri_mut() = parts
+        .uri
+        .path_and_query()
+        .map_or("/", http::uri::PathAndQuery::as_str)
+        .parse()?;
+
+    for (name, value) in &parts.headers {
+        converted.headers_mut().append(
+            HeaderName::from_bytes(name.as_str().as_bytes())?,
+            HeaderValue::from_bytes(value.as_bytes())?,
+        );
+    }
+
+    if let Some(authority) = parts.uri.authority() {
+        if !converted.headers().contains_key(HOST) {
+            converted
+                .headers_mut()
+                .insert(HOST, HeaderValue::from_str(authority.as_str())?);
+        }
+    }
+
+    Ok(converted)
+}
+
+/// Resolver for QUIC handshakes, which takes the certificates from the shared resolver.
+struct QuicResolver(Arc<Resolver<SniResolver>>);
+
+impl Debug for QuicResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuicResolver").finish_non_exhaustive()
+    }
+}
+
+impl quic_rustls::server::ResolvesServerCert for QuicResolver {
+    fn resolve(
+        &self,
+        client_hello: quic_rustls::server::ClientHello<'_>,
+    ) -> Option<Arc<quic_rustls::sign::CertifiedKey>> {
+        let schemes = client_hello
+            .signature_schemes()
+            .iter()
+            .map(|&scheme| rustls::SignatureScheme::from(u16::from(scheme)))
+            .collect::<Vec<_>>();
+        let certkey = self.0.load().select(client_hello.server_name(), &schemes)?;
+
+        let mut converted = quic_rustls::sign::CertifiedKey::new(
+            certkey
+                .cert
+                .iter()
+                .map(|cert| CertificateDer::from(cert.0.clone()))
+                .collect(),
+            Arc::new(QuicSigningKey(certkey.key.clone())),
+        );
+        converted.ocsp.clone_from(&certkey.ocsp);
+
+        Some(Arc::new(converted))
+    }
+}
+
+struct QuicSigningKey(Arc<dyn rustls::sign::SigningKey>);
+
+impl Debug for QuicSigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuicSigningKey").finish_non_exhaustive()
+    }
+}
+
+impl quic_rustls::sign::SigningKey for QuicSigningKey {
+    fn choose_scheme(
+        &self,
+        offered: &[quic_rustls::SignatureScheme],
+    ) -> Option<Box<dyn quic_rustls::sign::Signer>> {
+        let offered = offered
+            .iter()
+            .map(|&scheme| rustls::SignatureScheme::from(u16::from(scheme)))
+            .collect::<Vec<_>>();
+
+        self.0
+            .choose_scheme(&offered)
+            .map(|signer| Box::new(QuicSigner(signer)) as _)
+    }
+
+    fn algorithm(&self) -> quic_rustls::SignatureAlgorithm {
+        quic_rustls::SignatureAlgorithm::from(self.0.algorithm().get_u8())
+    }
+}
+
+struct QuicSigner(Box<dyn rustls::sign::Signer>);
+
+impl Debug for QuicSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuicSigner").finish_non_exhaustive()
+    }
+}
+
+impl quic_rustls::sign::Signer for QuicSigner {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, quic_rustls::Error> {
+        self.0
+            .sign(message)
+            .map_err(|e| quic_rustls::Error::General(e.to_string()))
+    }
+
+    fn scheme(&self) -> quic_rustls::SignatureScheme {
+        quic_rustls::SignatureScheme::from(self.0.scheme().get_u16())
+    }
+}
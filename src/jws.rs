@@ -0,0 +1,111 @@
+//! Signed ACME requests for the parts of the protocol that acme-lib doesn't cover, like account
+//! management.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use eyre::{eyre, Result};
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    ec::{EcGroup, EcKey},
+    ecdsa::EcdsaSig,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    sha::sha256,
+    sign::Signer,
+};
+use serde_json::{json, Value};
+
+/// Byte length of a P-256 coordinate or signature component.
+const P256_LEN: i32 = 32;
+
+/// ACME account key, which is always an ECDSA P-256 key, like the ones acme-lib creates.
+pub struct AccountKey {
+    key: EcKey<Private>,
+}
+
+impl AccountKey {
+    pub fn generate() -> Result<Self> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        Ok(Self {
+            key: EcKey::generate(&group)?,
+        })
+    }
+
+    pub fn from_pem(pem: &[u8]) -> Result<Self> {
+        Ok(Self {
+            key: EcKey::private_key_from_pem(pem)?,
+        })
+    }
+
+    pub fn to_pem(&self) -> Result<Vec<u8>> {
+        self.key.private_key_to_pem().map_err(Into::into)
+    }
+
+    /// Public part of the key as JSON web key.
+    pub fn jwk(&self) -> Result<Value> {
+        let mut ctx = BigNumContext::new()?;
+        let mut x = BigNum::new()?;
+        let mut y = BigNum::new()?;
+
+        self.key
+            .public_key()
+            .affine_coordinates(self.key.group(), &mut x, &mut y, &mut ctx)?;
+
+        Ok(json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(x.to_vec_padded(P256_LEN)?),
+            "y": URL_SAFE_NO_PAD.encode(y.to_vec_padded(P256_LEN)?),
+        }))
+    }
+
+    /// Sign the payload in the flattened JSON serialization, as required by ACME. The `protected`
+    /// header gets the algorithm added.
+    pub fn sign(&self, mut protected: Value, payload: &Value) -> Result<Value> {
+        protected
+            .as_object_mut()
+            .ok_or_else(|| eyre!("protected header must be an object"))?
+            .insert("alg".to_owned(), "ES256".into());
+
+        let protected = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&protected)?);
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(payload)?);
+
+        let digest = sha256(format!("{protected}.{payload}").as_bytes());
+        let sig = EcdsaSig::sign(&digest, &self.key)?;
+
+        let mut signature = sig.r().to_vec_padded(P256_LEN)?;
+        signature.extend(sig.s().to_vec_padded(P256_LEN)?);
+
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature),
+        }))
+    }
+
+    /// Binding of this key to an existing account at the CA, signed with the MAC key that the CA
+    /// handed out together with the key ID.
+    pub fn external_account_binding(
+        &self,
+        key_id: &str,
+        hmac_key: &str,
+        url: &str,
+    ) -> Result<Value> {
+        let hmac_key = URL_SAFE_NO_PAD.decode(hmac_key.trim_end_matches('='))?;
+
+        let protected = URL_SAFE_NO_PAD.encode(serde_json::to_vec(
+            &json!({ "alg": "HS256", "kid": key_id, "url": url }),
+        )?);
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&self.jwk()?)?);
+
+        let key = PKey::hmac(&hmac_key)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(format!("{protected}.{payload}").as_bytes())?;
+
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signer.sign_to_vec()?),
+        }))
+    }
+}
@@ -0,0 +1,177 @@
+//! Listening sockets, which are taken over from systemd socket activation or from the previous
+//! process during an upgrade, instead of binding new ones for the same addresses.
+
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, UdpSocket},
+};
+
+use eyre::{Result, WrapErr};
+use listenfd::ListenFd;
+use log::{info, warn};
+use parking_lot::Mutex;
+
+pub enum Socket {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+impl Socket {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr(),
+            Self::Udp(socket) => socket.local_addr(),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Tcp(listener) => listener.try_clone().map(Self::Tcp),
+            Self::Udp(socket) => socket.try_clone().map(Self::Udp),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Listeners {
+    /// Sockets that were passed in, but aren't used yet.
+    inherited: Mutex<Vec<Socket>>,
+    /// Duplicates of the sockets in use, to pass them on to the next process.
+    bound: Mutex<Vec<Socket>>,
+}
+
+impl Listeners {
+    /// Take over the sockets that systemd passed through `LISTEN_FDS`.
+    pub fn from_env() -> Result<Self> {
+        let mut fds = ListenFd::from_env();
+        let mut inherited = Vec::with_capacity(fds.len());
+
+        for idx in 0..fds.len() {
+            if let Ok(Some(listener)) = fds.take_tcp_listener(idx) {
+                inherited.push(Socket::Tcp(listener));
+            } else if let Some(socket) = fds
+                .take_udp_socket(idx)
+                .wrap_err("passed file descriptors must be TCP listeners or UDP sockets")?
+            {
+                inherited.push(Socket::Udp(socket));
+            }
+        }
+
+        if !inherited.is_empty() {
+            info!("took over {} sockets from systemd", inherited.len());
+        }
+
+        Ok(Self {
+            inherited: Mutex::new(inherited),
+            bound: Mutex::default(),
+        })
+    }
+
+    pub fn inherit(&self, sockets: Vec<Socket>) {
+        self.inherited.lock().extend(sockets);
+    }
+
+    /// Listener on the address, either one that was passed in or a newly bound one.
+    pub fn tcp(&self, addr: SocketAddr) -> Result<tokio::net::TcpListener> {
+        let listener = if let Some(Socket::Tcp(listener)) = self.take(addr, true) {
+            listener
+        } else {
+            TcpListener::bind(addr)
+                .wrap_err_with(|| format!("failed binding TCP listener on {addr}"))?
+        };
+
+        listener.set_nonblocking(true)?;
+        self.bound.lock().push(Socket::Tcp(listener.try_clone()?));
+
+        Ok(tokio::net::TcpListener::from_std(listener)?)
+    }
+
+    /// UDP socket on the address, either one that was passed in or a newly bound one.
+    pub fn udp(&self, addr: SocketAddr) -> Result<tokio::net::UdpSocket> {
+        let socket = if let Some(Socket::Udp(socket)) = self.take(addr, false) {
+            socket
+        } else {
+            UdpSocket::bind(addr)
+                .wrap_err_with(|| format!("failed binding UDP listener on {addr}"))?
+        };
+
+        socket.set_nonblocking(true)?;
+        self.bound.lock().push(Socket::Udp(socket.try_clone()?));
+
+        Ok(tokio::net::UdpSocket::from_std(socket)?)
+    }
+
+    /// UDP socket on the address that is never passed on to the next process, for protocols with
+    /// connection state that only this process knows.
+    pub fn udp_unshared(&self, addr: SocketAddr) -> io::Result<UdpSocket> {
+        match self.take(addr, false) {
+            Some(Socket::Udp(socket)) => Ok(socket),
+            _ => UdpSocket::bind(addr),
+        }
+    }
+
+    fn take(&self, addr: SocketAddr, tcp: bool) -> Option<Socket> {
+        let mut inherited = self.inherited.lock();
+        let idx = inherited.iter().position(|socket| {
+            matches!(socket, Socket::Tcp(_)) == tcp && socket.local_addr().ok() == Some(addr)
+        })?;
+
+        Some(inherited.swap_remove(idx))
+    }
+
+    /// Close the sockets that were passed in, but aren't configured anymore. Otherwise, they'd
+    /// keep accepting connections that nobody handles.
+    pub fn close_unused(&self) {
+        for socket in self.inherited.lock().drain(..) {
+            if let Ok(addr) = socket.local_addr() {
+                warn!(
+                    "closing passed in socket on {}, which isn't configured",
+                    addr
+                );
+            }
+        }
+    }
+
+    /// Close the duplicates kept for handoffs once draining starts. The servers close their own
+    /// sockets when they stop accepting, but as long as a duplicate is open, the kernel keeps
+    /// queueing connections that nobody accepts anymore.
+    pub fn close(&self) {
+        self.inherited.lock().clear();
+        self.bound.lock().clear();
+    }
+
+    /// Duplicates of all sockets in use.
+    pub fn sockets(&self) -> io::Result<Vec<Socket>> {
+        self.bound.lock().iter().map(Socket::try_clone).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn close_releases_duplicates() {
+        let listeners = Listeners::default();
+        let listener = listeners.tcp(([127, 0, 0, 1], 0).into()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        assert_eq!(1, listeners.sockets().unwrap().len());
+        assert!(TcpListener::bind(addr).is_err());
+
+        listeners.close();
+
+        assert!(listeners.sockets().unwrap().is_empty());
+        assert!(TcpListener::bind(addr).is_ok());
+    }
+
+    #[test]
+    fn unshared_socket_not_handed_over() {
+        let listeners = Listeners::default();
+        let socket = listeners.udp_unshared(([127, 0, 0, 1], 0).into()).unwrap();
+
+        assert!(listeners.sockets().unwrap().is_empty());
+        assert!(UdpSocket::bind(socket.local_addr().unwrap()).is_err());
+    }
+}
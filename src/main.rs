@@ -8,66 +8,272 @@
 #![warn(clippy::nursery)]
 #![allow(dead_code, clippy::module_name_repetitions)]
 
-use std::{env, sync::Arc};
+use std::{env, sync::Arc, time::Duration};
 
 use eyre::Result;
 use futures_util::future;
 use hyper::{server::conn::AddrIncoming, Client, Server};
 use log::info;
-use rustls::server::ServerConfig;
+use tokio::sync::mpsc;
 
+#[cfg(unix)]
+use crate::handoff::Handoff;
 use crate::{
-    acme::{Acme, ChallengeStorage},
-    cert::Resolver,
+    acme::AcmeHandle,
+    cert::{Resolver, SniResolver},
+    cli::Command,
+    forward::{TcpProxy, UdpProxy},
+    http3::Http3Server,
+    listeners::Listeners,
+    ocsp::Stapler,
+    ondemand::OnDemand,
+    renewal::Renewer,
     services::{MakeRedirect, MakeSvc},
-    tls::TlsAcceptor,
+    shutdown::Shutdown,
+    storage::Storage,
+    tickets::Ticketer,
+    tls::{TlsAcceptor, TlsConfigs},
 };
 
 mod acme;
 mod cert;
+mod cli;
+mod forward;
+#[cfg(unix)]
+mod handoff;
+mod http3;
+mod jws;
+mod listeners;
+mod ocsp;
+mod ondemand;
+mod passthrough;
+mod proxy_protocol;
+mod renewal;
 mod services;
 mod settings;
+mod shutdown;
+mod storage;
+mod tickets;
 mod tls;
 
-#[allow(clippy::similar_names)]
+#[allow(clippy::similar_names, clippy::too_many_lines)]
 #[tokio::main]
 async fn main() -> Result<()> {
     env::set_var("RUST_LOG", "warn,charon=trace");
     color_eyre::install()?;
     pretty_env_logger::init();
 
+    let command = cli::parse()?;
+    if matches!(command, Command::Help) {
+        cli::print_usage();
+        return Ok(());
+    }
+
     let settings = settings::load()?;
-    let challenges = ChallengeStorage::default();
+    let storage = Storage::new(&settings.storage)?;
+
+    if !matches!(command, Command::Serve) {
+        let acme = AcmeHandle::open(storage.clone(), settings.acme.clone()).await?;
+        return cli::run(command, &settings, &acme).await;
+    }
+
+    let acme = AcmeHandle::spawn(storage.clone(), settings.acme.clone())?;
 
-    let acme = Acme::new(challenges.clone(), &settings.acme.email)?;
+    let mut acme_groups = settings.acme_groups();
+    if settings.on_demand.is_some() {
+        for host in storage.on_demand_hosts()? {
+            if !acme_groups
+                .iter()
+                .any(|group| group.domains.contains(&host))
+            {
+                acme_groups.push(ondemand::group(host));
+            }
+        }
+    }
 
-    let certs = acme.load_certs(settings.routes.keys())?;
+    let certs = acme.load_certs(&acme_groups)?;
+    let static_certs = settings.static_groups();
 
-    let resolver = cert::load(&certs)?;
+    let mut resolver = cert::load(&acme_groups, &certs, &static_certs, &settings.fallback)?;
+    let on_demand_rx = settings.on_demand.as_ref().map(|_| {
+        let (tx, rx) = mpsc::channel(ondemand::QUEUE_SIZE);
+        resolver.set_on_demand(tx);
+        rx
+    });
     let resolver = Arc::new(Resolver::new(resolver));
 
-    let config = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_cert_resolver(resolver.clone());
+    tokio::spawn(cert::watch(resolver.clone(), static_certs));
+    let (renewals_tx, renewals_rx) = mpsc::unbounded_channel();
+    tokio::spawn(
+        Renewer::new(acme.clone(), resolver.clone(), acme_groups, &storage).run(renewals_rx),
+    );
+
+    if settings.ocsp.enabled {
+        tokio::spawn(Stapler::new(&settings.ocsp, resolver.clone()).run());
+    }
+
+    let on_demand_upstream = settings.on_demand.as_ref().map(|od| od.upstream.clone());
+    if let Some((on_demand, rx)) = settings.on_demand.zip(on_demand_rx) {
+        let on_demand = OnDemand::new(
+            on_demand,
+            acme.clone(),
+            resolver.clone(),
+            storage.clone(),
+            renewals_tx,
+        );
+        tokio::spawn(on_demand.run(rx));
+    }
+
+    let shutdown = Shutdown::new();
+    tokio::spawn(shutdown.clone().listen());
+
+    let listeners = Arc::new(Listeners::from_env()?);
+    #[cfg(unix)]
+    let handoff = settings
+        .handoff
+        .map(|handoff| Handoff::connect(handoff, listeners.clone(), shutdown.clone()))
+        .transpose()?;
+    #[cfg(unix)]
+    let took_over = handoff.as_ref().is_some_and(Handoff::took_over);
+    #[cfg(not(unix))]
+    let took_over = false;
+
+    let proxy_protocol = Arc::new(settings.proxy_protocol);
+    spawn_forwards(
+        settings.tcp,
+        settings.udp,
+        &listeners,
+        &proxy_protocol,
+        &shutdown,
+    )?;
+
+    let routes = Arc::new(settings.routes);
+
+    let configs = TlsConfigs::new(
+        resolver.clone(),
+        &settings.tls,
+        &settings.client_auth,
+        &settings.sessions,
+        spawn_ticketer(&settings.sessions, &storage)?,
+    )?;
 
     let https_addr = ([0, 0, 0, 0], 8443).into();
-    let incoming = AddrIncoming::bind(&https_addr)?;
-    let acceptor = TlsAcceptor::new(config, incoming);
+    let incoming = AddrIncoming::from_listener(listeners.tcp(https_addr)?)?;
+    let acceptor = TlsAcceptor::new(
+        configs,
+        incoming,
+        settings.passthrough,
+        proxy_protocol.clone(),
+        Duration::from_secs(settings.tls.handshake_timeout),
+        shutdown.clone(),
+    );
+    tokio::spawn(acceptor.failures().report());
 
-    let client = Client::new();
+    let make_svc = MakeSvc::new(
+        Client::new(),
+        routes,
+        on_demand_upstream,
+        Arc::new(settings.client_auth),
+        proxy_protocol.clone(),
+        settings.http3.as_ref(),
+    );
+    spawn_http3(
+        settings.http3.as_ref(),
+        &listeners,
+        resolver,
+        &make_svc,
+        &shutdown,
+        took_over,
+    )?;
 
-    let https_server = Server::builder(acceptor).serve(MakeSvc::new(client));
+    let https_server = Server::builder(acceptor)
+        .serve(make_svc)
+        .with_graceful_shutdown(shutdown.signaled());
 
     let http_addr = ([0, 0, 0, 0], 8080).into();
-    let http_server = Server::bind(&http_addr).serve(MakeRedirect::new(challenges));
+    let http_server = Server::builder(proxy_protocol::Acceptor::new(
+        AddrIncoming::from_listener(listeners.tcp(http_addr)?)?,
+        proxy_protocol,
+    ))
+    .serve(MakeRedirect::new(storage))
+    .with_graceful_shutdown(shutdown.signaled());
+
+    listeners.close_unused();
+    #[cfg(unix)]
+    if let Some(handoff) = handoff {
+        tokio::spawn(handoff.run());
+    }
+
+    let signaled = shutdown.signaled();
+    tokio::spawn(async move {
+        signaled.await;
+        listeners.close();
+    });
 
     info!("listening on {} for HTTP", http_addr);
     info!("listening on {} for HTTPS", https_addr);
 
-    let ret = future::join(https_server, http_server).await;
-    ret.0?;
-    ret.1?;
+    let servers = future::try_join(https_server, http_server);
+    let drain_timeout = Duration::from_secs(settings.shutdown.drain_timeout);
+    if let Some(ret) = shutdown.serve(servers, drain_timeout).await {
+        ret?;
+    }
+
+    Ok(())
+}
+
+fn spawn_ticketer(
+    settings: &settings::Sessions,
+    storage: &Storage,
+) -> Result<Option<Arc<Ticketer>>> {
+    if !settings.tickets {
+        return Ok(None);
+    }
+
+    let ticketer = Arc::new(Ticketer::new(settings, storage.clone())?);
+    tokio::spawn(ticketer.clone().run());
+
+    Ok(Some(ticketer))
+}
+
+fn spawn_forwards(
+    tcp: Vec<settings::Tcp>,
+    udp: Vec<settings::Udp>,
+    listeners: &Listeners,
+    proxy_protocol: &Arc<settings::ProxyProtocol>,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    for tcp in tcp {
+        tokio::spawn(
+            TcpProxy::bind(tcp, listeners, proxy_protocol.clone(), shutdown.clone())?.run(),
+        );
+    }
+    for udp in udp {
+        tokio::spawn(UdpProxy::bind(udp, listeners, shutdown.clone())?.run());
+    }
+
+    Ok(())
+}
+
+fn spawn_http3(
+    settings: Option<&settings::Http3>,
+    listeners: &Listeners,
+    resolver: Arc<Resolver<SniResolver>>,
+    make_svc: &MakeSvc,
+    shutdown: &Shutdown,
+    took_over: bool,
+) -> Result<()> {
+    if let Some(settings) = settings {
+        let server = Http3Server::bind(
+            settings,
+            listeners,
+            resolver,
+            make_svc.clone(),
+            shutdown.clone(),
+            took_over,
+        )?;
+        tokio::spawn(server.run());
+    }
 
     Ok(())
 }
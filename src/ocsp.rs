@@ -0,0 +1,359 @@
+//! OCSP stapling for served certificates.
+
+use std::{fmt::Write, fs, path::PathBuf, sync::Arc, time::Duration};
+
+use chrono::{NaiveDateTime, Utc};
+use eyre::{bail, ensure, eyre, Result};
+use hyper::{
+    body,
+    client::HttpConnector,
+    header::{CONTENT_TYPE, LOCATION},
+    Body, Client, Request,
+};
+use log::{debug, info, warn};
+use openssl::{
+    hash::MessageDigest,
+    ocsp::{OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus},
+    sha,
+    stack::Stack,
+    x509::{store::X509StoreBuilder, verify::X509VerifyFlags, X509},
+};
+use rustls::sign::CertifiedKey;
+use url::Url;
+
+use crate::{
+    cert::{Resolver, SniResolver},
+    settings,
+};
+
+const CACHE_DIR: &str = "temp/ocsp";
+
+/// Background task that keeps OCSP responses of all certificates in the resolver up to date.
+pub struct Stapler {
+    responder: Option<String>,
+    resolver: Arc<Resolver<SniResolver>>,
+    client: Client<HttpConnector>,
+}
+
+impl Stapler {
+    pub fn new(settings: &settings::Ocsp, resolver: Arc<Resolver<SniResolver>>) -> Self {
+        Self {
+            responder: settings.responder.clone(),
+            resolver,
+            client: Client::new(),
+        }
+    }
+
+    /// Refresh the responses once an hour, stapling them to the certificates in the resolver.
+    pub async fn run(self) {
+        if let Err(e) = fs::create_dir_all(CACHE_DIR) {
+            warn!("failed creating OCSP cache directory: {}", e);
+            return;
+        }
+
+        let mut interval = tokio::time::interval(Duration::from_hours(1));
+
+        loop {
+            interval.tick().await;
+
+            for certkey in self.resolver.load().certkeys() {
+                match self.refresh(&certkey).await {
+                    Ok(Some(response)) => self
+                        .resolver
+                        .update(|inner| inner.staple(&certkey, response.clone())),
+                    Ok(None) => {}
+                    Err(e) => warn!("failed refreshing OCSP response: {:?}", e),
+                }
+            }
+        }
+    }
+
+    /// Get a new OCSP response for the certificate, either from the disk cache or the responder.
+    /// Nothing is returned if the current response is still fresh or the certificate doesn't
+    /// support OCSP.
+    async fn refresh(&self, certkey: &CertifiedKey) -> Result<Option<Vec<u8>>> {
+        let Some(query) = Query::new(certkey, self.responder.as_deref())? else {
+            return Ok(None);
+        };
+
+        if let Some(current) = &certkey.ocsp {
+            if query.is_fresh(current) {
+                return Ok(None);
+            }
+        }
+
+        if let Ok(cached) = fs::read(&query.cache_path) {
+            if query.is_fresh(&cached) {
+                return Ok(Some(cached));
+            }
+        }
+
+        let response = self.fetch(&query).await?;
+        query.validate(&response)?;
+        fs::write(&query.cache_path, &response)?;
+
+        info!("fetched new OCSP response from {}", query.url);
+
+        Ok(Some(response))
+    }
+
+    async fn fetch(&self, query: &Query) -> Result<Vec<u8>> {
+        let mut url = query.url.clone();
+
+        // Follow a few redirects, as some responders are served behind CDNs.
+        for _ in 0..3 {
+            let req = Request::post(&url)
+                .header(CONTENT_TYPE, "application/ocsp-request")
+                .body(Body::from(query.request.clone()))?;
+            let resp = self.client.request(req).await?;
+
+            if resp.status().is_redirection() {
+                let location = resp
+                    .headers()
+                    .get(LOCATION)
+                    .and_then(|v| v.to_str().ok())
+                    .ok_or_else(|| eyre!("redirect from OCSP responder without location"))?;
+                url = Url::parse(&url)?.join(location)?.into();
+                continue;
+            }
+
+            ensure!(
+                resp.status().is_success(),
+                "OCSP responder replied with status {}",
+                resp.status()
+            );
+
+            return Ok(body::to_bytes(resp.into_body()).await?.to_vec());
+        }
+
+        bail!("too many redirects from OCSP responder")
+    }
+}
+
+/// Everything needed to request and validate an OCSP response for a single certificate.
+struct Query {
+    url: String,
+    request: Vec<u8>,
+    cert_id: OcspCertId,
+    issuer: X509,
+    cache_path: PathBuf,
+}
+
+impl Query {
+    fn new(certkey: &CertifiedKey, responder: Option<&str>) -> Result<Option<Self>> {
+        let (Some(leaf), Some(issuer)) = (certkey.cert.first(), certkey.cert.get(1)) else {
+            return Ok(None);
+        };
+
+        let leaf_der = &leaf.0;
+        let leaf = X509::from_der(leaf_der)?;
+        let issuer = X509::from_der(&issuer.0)?;
+
+        let url = if let Some(responder) = responder {
+            responder.to_owned()
+        } else if let Some(url) = leaf.ocsp_responders()?.iter().next() {
+            url.to_string()
+        } else {
+            debug!("certificate has no OCSP responder");
+            return Ok(None);
+        };
+
+        let cert_id = || OcspCertId::from_cert(MessageDigest::sha1(), &leaf, &issuer);
+
+        let mut request = OcspRequest::new()?;
+        request.add_id(cert_id()?)?;
+
+        let cache_name =
+            sha::sha256(leaf_der)
+                .iter()
+                .fold(String::with_capacity(64), |mut name, b| {
+                    write!(name, "{b:02x}").ok();
+                    name
+                });
+
+        Ok(Some(Self {
+            url,
+            request: request.to_der()?,
+            cert_id: cert_id()?,
+            issuer,
+            cache_path: PathBuf::from(format!("{CACHE_DIR}/{cache_name}.der")),
+        }))
+    }
+
+    /// Check that the response is valid and more than half of its validity period remains.
+    fn is_fresh(&self, response: &[u8]) -> bool {
+        self.validate(response)
+            .is_ok_and(|(this_update, next_update)| {
+                let now = Utc::now().naive_utc();
+                next_update - now > (next_update - this_update) / 2
+            })
+    }
+
+    /// Verify the response's signature and status, returning its `thisUpdate` and `nextUpdate`
+    /// times.
+    fn validate(&self, response: &[u8]) -> Result<(NaiveDateTime, NaiveDateTime)> {
+        let response = OcspResponse::from_der(response)?;
+        ensure!(
+            response.status() == OcspResponseStatus::SUCCESSFUL,
+            "unsuccessful OCSP response"
+        );
+
+        let basic = response.basic()?;
+
+        let mut certs = Stack::new()?;
+        certs.push(self.issuer.clone())?;
+
+        let mut store = X509StoreBuilder::new()?;
+        store.add_cert(self.issuer.clone())?;
+        store.set_flags(X509VerifyFlags::PARTIAL_CHAIN)?;
+
+        basic.verify(&certs, &store.build(), OcspFlag::TRUST_OTHER)?;
+
+        let status = basic
+            .find_status(&self.cert_id)
+            .ok_or_else(|| eyre!("OCSP response doesn't contain the certificate"))?;
+
+        ensure!(
+            status.status == OcspCertStatus::GOOD,
+            "certificate status is not good"
+        );
+        status.check_validity(300, None)?;
+
+        Ok((
+            parse_time(&status.this_update.to_string())?,
+            parse_time(&status.next_update.to_string())?,
+        ))
+    }
+}
+
+fn parse_time(value: &str) -> Result<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(value, "%b %e %H:%M:%S %Y GMT").map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, net::SocketAddr};
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Response, Server, StatusCode,
+    };
+    use rustls::sign;
+
+    use super::*;
+
+    const CA: &[u8] = include_bytes!("../testdata/ocsp/ca.pem");
+    const LEAF: &[u8] = include_bytes!("../testdata/ocsp/leaf.pem");
+    const LEAF_KEY: &[u8] = include_bytes!("../testdata/ocsp/leaf-key.pem");
+    const OTHER: &[u8] = include_bytes!("../testdata/ocsp/other.pem");
+    /// Recorded response of the test CA, which reports the leaf as good until 2126.
+    const RESPONSE: &[u8] = include_bytes!("../testdata/ocsp/response.der");
+
+    fn certkey(chain: &[&[u8]]) -> CertifiedKey {
+        let certs = chain
+            .iter()
+            .flat_map(|pem| rustls_pemfile::certs(&mut &**pem).unwrap())
+            .map(rustls::Certificate)
+            .collect();
+        let key = rustls_pemfile::pkcs8_private_keys(&mut &*LEAF_KEY)
+            .unwrap()
+            .remove(0);
+
+        CertifiedKey::new(
+            certs,
+            sign::any_supported_type(&rustls::PrivateKey(key)).unwrap(),
+        )
+    }
+
+    /// Responder that redirects `/redirect` to `/ocsp`, which answers with the recorded response.
+    fn spawn_responder() -> SocketAddr {
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let resp = match req.uri().path() {
+                    "/redirect" => Response::builder()
+                        .status(StatusCode::TEMPORARY_REDIRECT)
+                        .header(LOCATION, "/ocsp"),
+                    "/ocsp"
+                        if req
+                            .headers()
+                            .get(CONTENT_TYPE)
+                            .map(hyper::header::HeaderValue::as_bytes)
+                            == Some(b"application/ocsp-request") =>
+                    {
+                        let request = body::to_bytes(req.into_body()).await.unwrap();
+                        OcspRequest::from_der(&request).unwrap();
+
+                        return Ok::<_, Infallible>(Response::new(Body::from(RESPONSE)));
+                    }
+                    _ => Response::builder().status(StatusCode::BAD_REQUEST),
+                };
+
+                Ok(resp.body(Body::empty()).unwrap())
+            }))
+        }));
+
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn stapler(responder: String) -> Stapler {
+        Stapler::new(
+            &settings::Ocsp {
+                enabled: true,
+                responder: Some(responder),
+            },
+            Arc::new(Resolver::new(SniResolver::default())),
+        )
+    }
+
+    #[tokio::test]
+    async fn fetch_from_local_responder() {
+        let addr = spawn_responder();
+        let stapler = stapler(format!("http://{addr}/redirect"));
+        let query = Query::new(&certkey(&[LEAF, CA]), stapler.responder.as_deref())
+            .unwrap()
+            .unwrap();
+
+        let response = stapler.fetch(&query).await.unwrap();
+        let (this_update, next_update) = query.validate(&response).unwrap();
+
+        assert!(this_update < next_update);
+        assert!(query.is_fresh(&response));
+    }
+
+    #[tokio::test]
+    async fn fetch_fails_on_error_status() {
+        let addr = spawn_responder();
+        let stapler = stapler(format!("http://{addr}/unknown"));
+        let query = Query::new(&certkey(&[LEAF, CA]), stapler.responder.as_deref())
+            .unwrap()
+            .unwrap();
+
+        assert!(stapler.fetch(&query).await.is_err());
+    }
+
+    #[test]
+    fn validate_rejects_response_for_other_certificate() {
+        let query = Query::new(&certkey(&[OTHER, CA]), Some("http://127.0.0.1"))
+            .unwrap()
+            .unwrap();
+
+        assert!(query.validate(RESPONSE).is_err());
+        assert!(!query.is_fresh(RESPONSE));
+    }
+
+    #[test]
+    fn validate_rejects_invalid_response() {
+        let query = Query::new(&certkey(&[LEAF, CA]), None).unwrap().unwrap();
+
+        assert_eq!("http://ocsp.example.com", query.url);
+        assert!(query.validate(b"not an OCSP response").is_err());
+        assert!(query.validate(&RESPONSE[..RESPONSE.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn query_requires_issuer() {
+        assert!(Query::new(&certkey(&[LEAF]), None).unwrap().is_none());
+    }
+}
@@ -0,0 +1,164 @@
+//! On-demand certificates for hosts that are not known up front.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ahash::RandomState;
+use eyre::{eyre, Result};
+use hyper::{client::HttpConnector, Client, Uri};
+use log::{info, warn};
+use tokio::sync::mpsc;
+use url::Url;
+
+use crate::{
+    acme::AcmeHandle,
+    cert::{self, Resolver, SniResolver},
+    settings::{self, KeyType},
+    storage::Storage,
+};
+
+/// Amount of unknown host names that can be queued before further ones are dropped.
+pub const QUEUE_SIZE: usize = 64;
+
+const ORDER_WINDOW: Duration = Duration::from_hours(1);
+/// Time the `ask` endpoint has to reply, so a slow one doesn't hold up the whole queue.
+const ASK_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct OnDemand {
+    settings: settings::OnDemand,
+    acme: AcmeHandle,
+    resolver: Arc<Resolver<SniResolver>>,
+    storage: Storage,
+    /// Receives the certificate groups of issued hosts, to renew them like configured ones.
+    renewals: mpsc::UnboundedSender<settings::Certificate>,
+    client: Client<HttpConnector>,
+    failures: HashMap<String, Instant, RandomState>,
+    orders: VecDeque<Instant>,
+}
+
+impl OnDemand {
+    pub fn new(
+        settings: settings::OnDemand,
+        acme: AcmeHandle,
+        resolver: Arc<Resolver<SniResolver>>,
+        storage: Storage,
+        renewals: mpsc::UnboundedSender<settings::Certificate>,
+    ) -> Self {
+        Self {
+            settings,
+            acme,
+            resolver,
+            storage,
+            renewals,
+            client: Client::new(),
+            failures: HashMap::default(),
+            orders: VecDeque::new(),
+        }
+    }
+
+    /// Process host names reported by the resolver one by one, until the sender is dropped.
+    pub async fn run(mut self, mut hosts: mpsc::Receiver<String>) {
+        while let Some(host) = hosts.recv().await {
+            if self.resolver.load().contains(&host) || self.recently_failed(&host) {
+                continue;
+            }
+
+            if let Err(e) = self.issue(&host).await {
+                warn!("failed issuing on-demand certificate for {}: {:?}", host, e);
+                self.failures.insert(host, Instant::now());
+            }
+        }
+    }
+
+    fn recently_failed(&mut self, host: &str) -> bool {
+        let ttl = Duration::from_secs(self.settings.failure_ttl);
+        self.failures.retain(|_, failed| failed.elapsed() < ttl);
+        self.failures.contains_key(host)
+    }
+
+    async fn issue(&mut self, host: &str) -> Result<()> {
+        if !self.is_allowed(host).await? {
+            return Err(eyre!("host is not allowed"));
+        }
+
+        while self
+            .orders
+            .front()
+            .is_some_and(|order| order.elapsed() >= ORDER_WINDOW)
+        {
+            self.orders.pop_front();
+        }
+
+        if self.orders.len() >= self.settings.max_orders_per_hour {
+            return Err(eyre!("order rate limit reached"));
+        }
+
+        self.orders.push_back(Instant::now());
+
+        let acme_cert = self
+            .acme
+            .request(vec![host.to_owned()], KeyType::default())
+            .await?;
+        let certkey = Arc::new(cert::load_acme(&acme_cert)?);
+
+        self.resolver
+            .update(|inner| inner.replace(host, certkey.clone()));
+
+        info!("issued on-demand certificate for {}", host);
+
+        let storage = self.storage.clone();
+        let owned_host = host.to_owned();
+        tokio::task::spawn_blocking(move || storage.add_on_demand_host(&owned_host)).await??;
+        self.renewals.send(group(host.to_owned())).ok();
+
+        Ok(())
+    }
+
+    async fn is_allowed(&self, host: &str) -> Result<bool> {
+        if self
+            .settings
+            .allow
+            .iter()
+            .any(|pattern| matches(pattern, host))
+        {
+            return Ok(true);
+        }
+
+        let Some(ask) = &self.settings.ask else {
+            return Ok(false);
+        };
+
+        let uri = Url::parse_with_params(ask, [("domain", host)])?
+            .as_str()
+            .parse::<Uri>()?;
+        let resp = tokio::time::timeout(ASK_TIMEOUT, self.client.get(uri))
+            .await
+            .map_err(|_| eyre!("ask endpoint didn't reply in time"))??;
+
+        Ok(resp.status().is_success())
+    }
+}
+
+/// Certificate group of an on-demand host, which always has a single name and key type.
+pub fn group(host: String) -> settings::Certificate {
+    settings::Certificate {
+        domains: vec![host],
+        key_types: vec![KeyType::default()],
+        cert: None,
+        key: None,
+    }
+}
+
+/// Check whether a host matches an exact or wildcard (`*.example.com`) pattern.
+fn matches(pattern: &str, host: &str) -> bool {
+    pattern.strip_prefix("*.").map_or_else(
+        || pattern.eq_ignore_ascii_case(host),
+        |parent| {
+            host.split_once('.')
+                .is_some_and(|(_, host_parent)| host_parent.eq_ignore_ascii_case(parent))
+        },
+    )
+}
@@ -0,0 +1,74 @@
+//! Passthrough of TLS connections to upstreams that terminate TLS themselves, selected by the SNI
+//! name of the client hello without decrypting anything.
+
+use std::{net::SocketAddr, time::Duration};
+
+use futures_util::future::poll_fn;
+use hyper::server::conn::AddrStream;
+use log::info;
+use rustls::server::Acceptor;
+use tokio::{
+    io::{self, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::forward;
+
+/// Largest client hello that is looked at, which is the maximum size of a single TLS record.
+const PEEK_LIMIT: usize = 16 * 1024 + 5;
+/// Delay before peeking again, if the client hello didn't fully arrive yet.
+const PEEK_RETRY: Duration = Duration::from_millis(10);
+
+/// Read the SNI name from the client hello, leaving the data in the socket for the actual
+/// handshake. Nothing is returned for clients that don't speak TLS or send no SNI name.
+pub async fn server_name(stream: &mut AddrStream) -> io::Result<Option<String>> {
+    let mut buf = vec![0; PEEK_LIMIT];
+
+    loop {
+        let mut read_buf = ReadBuf::new(&mut buf);
+        poll_fn(|cx| stream.poll_peek(cx, &mut read_buf)).await?;
+        let peeked = read_buf.filled();
+
+        if peeked.is_empty() {
+            return Ok(None);
+        }
+
+        let mut acceptor = Acceptor::default();
+        if acceptor.read_tls(&mut &*peeked).is_err() {
+            return Ok(None);
+        }
+
+        match acceptor.accept() {
+            Ok(Some(accepted)) => {
+                return Ok(accepted
+                    .client_hello()
+                    .server_name()
+                    .map(str::to_ascii_lowercase))
+            }
+            Ok(None) if peeked.len() < PEEK_LIMIT => tokio::time::sleep(PEEK_RETRY).await,
+            Ok(None) | Err(_) => return Ok(None),
+        }
+    }
+}
+
+/// Copy the raw connection to and from the upstream, until either side closes it. The PROXY
+/// protocol `header` is sent to the upstream first.
+pub async fn splice(
+    stream: AddrStream,
+    remote_addr: SocketAddr,
+    upstream: &str,
+    header: Option<Vec<u8>>,
+) -> io::Result<()> {
+    let mut server = TcpStream::connect(upstream).await?;
+    if let Some(header) = header {
+        server.write_all(&header).await?;
+    }
+    let (from_client, from_server) = forward::copy(stream.into_inner(), server, None).await?;
+
+    info!(
+        "passthrough from {} to {} closed, client wrote {} bytes, received {} bytes",
+        remote_addr, upstream, from_client, from_server
+    );
+
+    Ok(())
+}
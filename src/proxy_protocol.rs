@@ -0,0 +1,398 @@
+//! PROXY protocol, which passes on the original client address at the start of a connection, in
+//! the text format of version 1 and the binary one of version 2.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use hyper::server::{
+    accept::Accept,
+    conn::{AddrIncoming, AddrStream},
+};
+use log::debug;
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    sync::mpsc,
+};
+
+use crate::settings::{self, ProxyProtocolVersion};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest possible version 1 header, including the line break.
+const V1_MAX_LEN: usize = 107;
+/// Time a trusted source has to send the header.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Read the header at the start of the stream, returning the client address. Nothing is returned
+/// for connections the load balancer opened itself, like health checks, or unknown address
+/// families.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0; V2_SIGNATURE.len()];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+/// Read the rest of a version 1 header, which looks like
+/// `PROXY TCP4 <client ip> <proxy ip> <client port> <proxy port>\r\n`.
+async fn read_v1<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    start: &[u8],
+) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY protocol header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol header isn't valid text"))?;
+    let mut fields = line.split(' ').skip(1);
+
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("unsupported PROXY protocol address family")),
+    }
+
+    let ip = fields.next().and_then(|ip| ip.parse::<IpAddr>().ok());
+    let port = fields.nth(1).and_then(|port| port.parse::<u16>().ok());
+
+    ip.zip(port)
+        .map(|addr| Some(addr.into()))
+        .ok_or_else(|| invalid("invalid address in PROXY protocol header"))
+}
+
+/// Read the rest of a version 2 header, which continues with the version and command, the address
+/// family, the length of the addresses and finally the addresses themselves.
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut head = [0; 4];
+    stream.read_exact(&mut head).await?;
+
+    let mut addrs = vec![0; usize::from(u16::from_be_bytes([head[2], head[3]]))];
+    stream.read_exact(&mut addrs).await?;
+
+    match head[0] {
+        // Local command, for connections of the load balancer itself.
+        0x20 => return Ok(None),
+        0x21 => {}
+        _ => return Err(invalid("unsupported PROXY protocol version or command")),
+    }
+
+    let addr = match head[1] >> 4 {
+        0x1 if addrs.len() >= 12 => {
+            let ip = <[u8; 4]>::try_from(&addrs[..4]).map(IpAddr::from);
+            ip.ok()
+                .map(|ip| (ip, u16::from_be_bytes([addrs[8], addrs[9]])))
+        }
+        0x2 if addrs.len() >= 36 => {
+            let ip = <[u8; 16]>::try_from(&addrs[..16]).map(IpAddr::from);
+            ip.ok()
+                .map(|ip| (ip, u16::from_be_bytes([addrs[32], addrs[33]])))
+        }
+        _ => None,
+    };
+
+    Ok(addr.map(Into::into))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Header that tells an upstream about the client address `src`, which connected to `dst`.
+pub fn header(version: ProxyProtocolVersion, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    match version {
+        ProxyProtocolVersion::V1 => {
+            let family = match (src, dst) {
+                (SocketAddr::V4(_), SocketAddr::V4(_)) => "TCP4",
+                (SocketAddr::V6(_), SocketAddr::V6(_)) => "TCP6",
+                _ => return b"PROXY UNKNOWN\r\n".to_vec(),
+            };
+
+            format!(
+                "PROXY {family} {} {} {} {}\r\n",
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port()
+            )
+            .into_bytes()
+        }
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            header.push(0x21);
+
+            if let (SocketAddr::V4(src), SocketAddr::V4(dst)) = (src, dst) {
+                header.push(0x11);
+                header.extend(12_u16.to_be_bytes());
+                header.extend(src.ip().octets());
+                header.extend(dst.ip().octets());
+            } else {
+                let ipv6 = |addr: SocketAddr| match addr.ip() {
+                    IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                    IpAddr::V6(ip) => ip,
+                };
+
+                header.push(0x21);
+                header.extend(36_u16.to_be_bytes());
+                header.extend(ipv6(src).octets());
+                header.extend(ipv6(dst).octets());
+            }
+
+            header.extend(src.port().to_be_bytes());
+            header.extend(dst.port().to_be_bytes());
+            header
+        }
+    }
+}
+
+/// Plain HTTP connection, after the PROXY protocol header was read.
+pub struct ProxiedStream {
+    stream: AddrStream,
+    /// Address of the client, which a load balancer might have passed on through the PROXY
+    /// protocol.
+    pub remote_addr: SocketAddr,
+}
+
+impl AsyncRead for ProxiedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxiedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Acceptor for plain HTTP, that removes the headers of trusted sources before handing over the
+/// connection, if enabled for the HTTP listener. As with TLS, headers are read in separate tasks
+/// so slow clients don't hold up others.
+pub struct Acceptor {
+    incoming: AddrIncoming,
+    settings: Arc<settings::ProxyProtocol>,
+    ready_tx: mpsc::UnboundedSender<ProxiedStream>,
+    ready_rx: mpsc::UnboundedReceiver<ProxiedStream>,
+}
+
+impl Acceptor {
+    pub fn new(incoming: AddrIncoming, settings: Arc<settings::ProxyProtocol>) -> Self {
+        let (ready_tx, ready_rx) = mpsc::unbounded_channel();
+
+        Self {
+            incoming,
+            settings,
+            ready_tx,
+            ready_rx,
+        }
+    }
+}
+
+impl Accept for Acceptor {
+    type Conn = ProxiedStream;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let pin = self.get_mut();
+
+        loop {
+            match Pin::new(&mut pin.incoming).poll_accept(cx) {
+                Poll::Ready(Some(Ok(mut stream))) => {
+                    let remote_addr = stream.remote_addr();
+                    if !pin.settings.http || !pin.settings.trusts(remote_addr.ip()) {
+                        return Poll::Ready(Some(Ok(ProxiedStream {
+                            stream,
+                            remote_addr,
+                        })));
+                    }
+
+                    let ready = pin.ready_tx.clone();
+                    tokio::spawn(async move {
+                        match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)).await {
+                            Ok(Ok(addr)) => {
+                                ready
+                                    .send(ProxiedStream {
+                                        stream,
+                                        remote_addr: addr.unwrap_or(remote_addr),
+                                    })
+                                    .ok();
+                            }
+                            Ok(Err(e)) => {
+                                debug!("invalid PROXY protocol header from {}: {}", remote_addr, e);
+                            }
+                            Err(_) => {
+                                debug!("no PROXY protocol header from {} in time", remote_addr);
+                            }
+                        }
+                    });
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => break,
+            }
+        }
+
+        pin.ready_rx.poll_recv(cx).map(|stream| stream.map(Ok))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut data: &[u8]) -> io::Result<Option<SocketAddr>> {
+        read_header(&mut data).await
+    }
+
+    fn addr(value: &str) -> SocketAddr {
+        value.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn v1() {
+        assert_eq!(
+            Some(addr("192.0.2.1:56324")),
+            read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /")
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            Some(addr("[2001:db8::1]:56324")),
+            read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n")
+                .await
+                .unwrap()
+        );
+        assert_eq!(None, read(b"PROXY UNKNOWN\r\n").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn v1_malformed() {
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324")
+            .await
+            .is_err());
+        assert!(read(b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n")
+            .await
+            .is_err());
+        assert!(read(b"PROXY TCP4 192.0.2 198.51.100.1 56324 443\r\n")
+            .await
+            .is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 443\r\n")
+            .await
+            .is_err());
+        assert!(
+            read(&[b"PROXY TCP4 ".as_slice(), &[b'1'; 120], b"\r\n"].concat())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([
+            0x21, 0x11, 0, 12, 192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 1, 0xbb,
+        ]);
+        assert_eq!(Some(addr("192.0.2.1:56324")), read(&header).await.unwrap());
+
+        // Local command of the load balancer's health checks.
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(None, read(&local).await.unwrap());
+
+        // Unix sockets carry no IP address.
+        let mut unix = V2_SIGNATURE.to_vec();
+        unix.extend([0x21, 0x31, 0, 216]);
+        unix.extend([0; 216]);
+        assert_eq!(None, read(&unix).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn v2_malformed() {
+        let mut truncated = V2_SIGNATURE.to_vec();
+        truncated.extend([0x21, 0x11, 0, 12, 192, 0, 2, 1]);
+        assert!(read(&truncated).await.is_err());
+
+        let mut version = V2_SIGNATURE.to_vec();
+        version.extend([0x11, 0x11, 0, 0]);
+        assert!(read(&version).await.is_err());
+
+        assert!(read(&V2_SIGNATURE[..8]).await.is_err());
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn header_round_trip() {
+        let dst = addr("198.51.100.1:443");
+
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for src in [addr("192.0.2.1:56324"), addr("[2001:db8::1]:56324")] {
+                let dst = if src.is_ipv4() {
+                    dst
+                } else {
+                    addr("[2001:db8::2]:443")
+                };
+
+                assert_eq!(
+                    Some(src),
+                    read(&header(version, src, dst)).await.unwrap(),
+                    "{version:?} {src}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn header_mixed_families() {
+        let src = addr("192.0.2.1:56324");
+        let dst = addr("[2001:db8::2]:443");
+
+        assert_eq!(
+            None,
+            read(&header(ProxyProtocolVersion::V1, src, dst))
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            Some(addr("[::ffff:192.0.2.1]:56324")),
+            read(&header(ProxyProtocolVersion::V2, src, dst))
+                .await
+                .unwrap()
+        );
+    }
+}
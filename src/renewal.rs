@@ -0,0 +1,313 @@
+//! Scheduling of certificate orders and renewals.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ahash::RandomState;
+use chrono::{DateTime, Utc};
+use eyre::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::{
+    acme::{self, AcmeHandle},
+    cert::{self, Resolver, SniResolver},
+    settings::{self, KeyType},
+    storage::{self, Storage},
+};
+
+const STATE_FILE: &str = "renewal.toml";
+
+/// Delay after the first failed order, doubled for every further failure.
+const BASE_DELAY: Duration = Duration::from_mins(5);
+/// Upper limit for the delay between failed orders.
+const MAX_DELAY: Duration = Duration::from_hours(24);
+/// Default interval to ask the CA for new renewal information.
+const POLL_INTERVAL: Duration = Duration::from_hours(6);
+
+/// Failure state that is persisted across restarts.
+#[derive(Default, Deserialize, Serialize)]
+struct State {
+    #[serde(default)]
+    backoff: HashMap<String, Backoff, RandomState>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Backoff {
+    failures: u32,
+    /// Unix timestamp before which no new order is placed.
+    retry_at: i64,
+}
+
+struct Schedule {
+    renew_at: DateTime<Utc>,
+    next_poll: Instant,
+}
+
+/// Background task that orders missing certificates and renews existing ones, swapping them into
+/// the resolver once issued.
+pub struct Renewer {
+    acme: AcmeHandle,
+    resolver: Arc<Resolver<SniResolver>>,
+    groups: Vec<settings::Certificate>,
+    state_path: PathBuf,
+    state: State,
+    schedules: HashMap<String, Schedule, RandomState>,
+    /// Stored certificates that are currently served, to notice the ones that other instances
+    /// sharing the storage ordered.
+    installed: HashMap<String, String, RandomState>,
+}
+
+impl Renewer {
+    pub fn new(
+        acme: AcmeHandle,
+        resolver: Arc<Resolver<SniResolver>>,
+        groups: Vec<settings::Certificate>,
+        storage: &Storage,
+    ) -> Self {
+        let state_path = storage.dir().join(STATE_FILE);
+        let state = fs::read(&state_path)
+            .ok()
+            .and_then(|data| basic_toml::from_slice(&data).ok())
+            .unwrap_or_default();
+
+        Self {
+            acme,
+            resolver,
+            groups,
+            state_path,
+            state,
+            schedules: HashMap::default(),
+            installed: HashMap::default(),
+        }
+    }
+
+    /// Check all certificates every few minutes. Groups that are added later, like the ones of
+    /// on-demand certificates, are checked from then on too.
+    pub async fn run(mut self, mut added: mpsc::UnboundedReceiver<settings::Certificate>) {
+        let mut interval = tokio::time::interval(Duration::from_mins(10));
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                Some(group) = added.recv() => {
+                    self.groups.push(group);
+                    continue;
+                }
+            }
+
+            for group in self.groups.clone() {
+                for &key_type in &group.key_types {
+                    self.check(&group.domains, key_type).await;
+                }
+            }
+        }
+    }
+
+    async fn check(&mut self, domains: &[String], key_type: KeyType) {
+        let Some(primary) = domains.first() else {
+            return;
+        };
+        let name = acme::persist_name(primary, key_type);
+
+        if let Err(e) = self.sync(&name, domains, key_type).await {
+            warn!("failed loading stored certificate for {}: {:?}", name, e);
+        }
+
+        if let Some(backoff) = self.state.backoff.get(&name) {
+            if Utc::now().timestamp() < backoff.retry_at {
+                return;
+            }
+        }
+
+        if let Some(schedule) = self.schedules.get(&name) {
+            if schedule.next_poll > Instant::now() && schedule.renew_at > Utc::now() {
+                return;
+            }
+        }
+
+        match self.plan(domains, key_type).await {
+            Ok(Some(schedule)) => {
+                let due = schedule.renew_at <= Utc::now();
+                self.schedules.insert(name.clone(), schedule);
+
+                if !due {
+                    return;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("failed checking renewal of {}: {:?}", name, e);
+                return;
+            }
+        }
+
+        let result = self.acme.request(domains.to_vec(), key_type).await;
+
+        match result.and_then(|acme_cert| self.install(&name, &acme_cert)) {
+            Ok(()) => info!("issued new certificate for {}", name),
+            Err(e) => {
+                let backoff = self.state.backoff.entry(name.clone()).or_insert(Backoff {
+                    failures: 0,
+                    retry_at: 0,
+                });
+                backoff.failures += 1;
+
+                let delay = chrono::Duration::from_std(backoff_delay(backoff.failures))
+                    .unwrap_or_else(|_| chrono::Duration::days(1));
+                let mut retry_at = Utc::now() + delay;
+                if let Some(until) = acme::rate_limited_until(&e) {
+                    retry_at = retry_at.max(until);
+                }
+                backoff.retry_at = retry_at.timestamp();
+
+                warn!(
+                    "failed ordering certificate for {} (attempt {}), retrying at {}: {:?}",
+                    name, backoff.failures, retry_at, e
+                );
+
+                self.save();
+            }
+        }
+    }
+
+    /// Serve the stored certificate if it changed, which happens when another instance that shares
+    /// the storage renewed it.
+    async fn sync(&mut self, name: &str, domains: &[String], key_type: KeyType) -> Result<()> {
+        let owned_domains = domains.to_vec();
+        let Some(acme_cert) = self
+            .acme
+            .run(move |acme| acme.load_cert(&owned_domains, key_type))
+            .await?
+        else {
+            return Ok(());
+        };
+
+        if self.installed.get(name).map(String::as_str) == Some(acme_cert.certificate()) {
+            return Ok(());
+        }
+
+        self.install(name, &acme_cert)
+    }
+
+    fn install(&mut self, name: &str, acme_cert: &acme::Certificate) -> Result<()> {
+        // Only the names the certificate covers, as a stored one might lack newly configured names
+        // until it's reordered.
+        let certkey = Arc::new(cert::load_acme(acme_cert)?);
+        self.resolver.update(|inner| {
+            for domain in acme_cert.domains() {
+                inner.renew(domain, certkey.clone());
+            }
+        });
+
+        self.installed
+            .insert(name.to_owned(), acme_cert.certificate().to_owned());
+        self.schedules.remove(name);
+        if self.state.backoff.remove(name).is_some() {
+            self.save();
+        }
+
+        Ok(())
+    }
+
+    /// Determine when the existing certificate should be renewed, picking a random point within
+    /// the suggested window to spread the load on the CA. Nothing is returned if there is no
+    /// certificate yet or it doesn't cover the configured host names, meaning it's due right away.
+    async fn plan(&self, domains: &[String], key_type: KeyType) -> Result<Option<Schedule>> {
+        let domains = domains.to_vec();
+
+        self.acme
+            .run(move |acme| {
+                let Some(cert) = acme.load_cert(&domains, key_type)? else {
+                    return Ok(None);
+                };
+                if !cert.covers(&domains) {
+                    info!(
+                        "host names of {:?} changed, ordering a new certificate",
+                        domains
+                    );
+                    return Ok(None);
+                }
+
+                let window = acme.renewal_window(&cert)?;
+                let span = (window.end - window.start).num_seconds().max(0);
+                #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+                let offset = (span as f64 * random()) as i64;
+
+                Ok(Some(Schedule {
+                    renew_at: window.start + chrono::Duration::seconds(offset),
+                    next_poll: Instant::now() + window.retry_after.unwrap_or(POLL_INTERVAL),
+                }))
+            })
+            .await
+    }
+
+    fn save(&self) {
+        let result = basic_toml::to_string(&self.state)
+            .map_err(eyre::Report::from)
+            .and_then(|data| storage::write_atomic(&self.state_path, data.as_bytes(), false));
+
+        if let Err(e) = result {
+            warn!("failed saving renewal state: {:?}", e);
+        }
+    }
+}
+
+/// Exponential backoff with jitter of ±20%, to avoid retrying many orders at the same time.
+fn backoff_delay(failures: u32) -> Duration {
+    let delay = BASE_DELAY
+        .saturating_mul(2_u32.saturating_pow(failures.saturating_sub(1)))
+        .min(MAX_DELAY);
+
+    delay.mul_f64(random().mul_add(0.4, 0.8))
+}
+
+/// Random number in the range `[0, 1)`, good enough for jitter.
+fn random() -> f64 {
+    #[allow(clippy::cast_precision_loss)]
+    let value = (RandomState::new().hash_one(Instant::now()) >> 11) as f64;
+    value / 9_007_199_254_740_992.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_jittered(expected: Duration, failures: u32) {
+        for _ in 0..100 {
+            let delay = backoff_delay(failures);
+            assert!(
+                delay >= expected.mul_f64(0.8) && delay <= expected.mul_f64(1.2),
+                "{delay:?} not within 20% of {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_per_failure() {
+        assert_jittered(BASE_DELAY, 0);
+        assert_jittered(BASE_DELAY, 1);
+        assert_jittered(BASE_DELAY * 2, 2);
+        assert_jittered(BASE_DELAY * 8, 4);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        assert_jittered(MAX_DELAY, 10);
+        assert_jittered(MAX_DELAY, 40);
+        assert_jittered(MAX_DELAY, u32::MAX);
+    }
+
+    #[test]
+    fn random_in_unit_range() {
+        for _ in 0..1000 {
+            assert!((0.0..1.0).contains(&random()));
+        }
+    }
+}
@@ -155,7 +155,7 @@ where
 
 struct DisplayOption<'a, T: Display>(&'a Option<T>);
 
-impl<'a, T: Display> Display for DisplayOption<'a, T> {
+impl<T: Display> Display for DisplayOption<'_, T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self.0 {
             Some(v) => v.fmt(f),
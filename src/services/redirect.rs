@@ -2,6 +2,8 @@
 
 use std::{
     convert::Infallible,
+    future::Future,
+    pin::Pin,
     task::{Context, Poll},
 };
 
@@ -15,70 +17,79 @@ use hyper::{
     },
     Body, Request, Response,
 };
-use tower::Service;
+use tower::{Service, ServiceBuilder};
 
-use crate::acme::ChallengeStorage;
+use super::log::{LogLayer, LogService};
+use crate::{acme::CHALLENGE_PATH, proxy_protocol::ProxiedStream, storage::Storage};
 
 pub struct Redirect {
-    challenges: ChallengeStorage,
+    storage: Storage,
 }
 
 impl Service<Request<Body>> for Redirect {
     type Response = Response<Body>;
     type Error = Infallible;
-    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Ok(()).into()
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        let rsp = Response::builder();
-
-        let host = req
-            .headers()
-            .get(HOST)
-            .and_then(|v| v.to_str().ok())
-            .and_then(|v| v.parse::<Uri>().ok());
-
-        let rsp = if let Some(host) = host.as_ref().and_then(Uri::host) {
-            let path_and_query = req.uri().path_and_query().map_or("/", PathAndQuery::as_str);
+        let storage = self.storage.clone();
 
-            if let Some(challenge) = self.challenges.read().get(host) {
-                if path_and_query == challenge.path {
-                    return future::ok(rsp.body(Body::from(challenge.proof.clone())).unwrap());
+        Box::pin(async move {
+            // Challenges are answered for any host, as the token alone identifies them.
+            if let Some(token) = req.uri().path().strip_prefix(CHALLENGE_PATH) {
+                if let Some(proof) = storage.challenge(token).await {
+                    return Ok(Response::new(Body::from(proof)));
                 }
             }
 
-            let location = format!(
-                "https://{}:{}{}",
-                host,
-                8443,
-                req.uri().path_and_query().map_or("/", PathAndQuery::as_str)
-            );
-
-            rsp.status(StatusCode::PERMANENT_REDIRECT)
-                .header(LOCATION, location)
-        } else {
-            rsp.status(StatusCode::NOT_FOUND)
-        };
-
-        future::ok(rsp.body(Body::empty()).unwrap())
+            Ok(redirect(&req))
+        })
     }
 }
 
+/// Redirect to the same location over HTTPS.
+fn redirect(req: &Request<Body>) -> Response<Body> {
+    let rsp = Response::builder();
+
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<Uri>().ok());
+
+    let rsp = if let Some(host) = host.as_ref().and_then(Uri::host) {
+        let location = format!(
+            "https://{}:{}{}",
+            host,
+            8443,
+            req.uri().path_and_query().map_or("/", PathAndQuery::as_str)
+        );
+
+        rsp.status(StatusCode::PERMANENT_REDIRECT)
+            .header(LOCATION, location)
+    } else {
+        rsp.status(StatusCode::NOT_FOUND)
+    };
+
+    rsp.body(Body::empty()).unwrap()
+}
+
 pub struct MakeRedirect {
-    challenges: ChallengeStorage,
+    storage: Storage,
 }
 
 impl MakeRedirect {
-    pub const fn new(challenges: ChallengeStorage) -> Self {
-        Self { challenges }
+    pub const fn new(storage: Storage) -> Self {
+        Self { storage }
     }
 }
 
-impl<T> Service<T> for MakeRedirect {
-    type Response = Redirect;
+impl Service<&ProxiedStream> for MakeRedirect {
+    type Response = LogService<Redirect>;
     type Error = Infallible;
     type Future = future::Ready<Result<Self::Response, Self::Error>>;
 
@@ -86,9 +97,13 @@ impl<T> Service<T> for MakeRedirect {
         Ok(()).into()
     }
 
-    fn call(&mut self, _req: T) -> Self::Future {
-        future::ok(Redirect {
-            challenges: self.challenges.clone(),
-        })
+    fn call(&mut self, conn: &ProxiedStream) -> Self::Future {
+        future::ok(
+            ServiceBuilder::new()
+                .layer(LogLayer::new(conn.remote_addr))
+                .service(Redirect {
+                    storage: self.storage.clone(),
+                }),
+        )
     }
 }
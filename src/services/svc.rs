@@ -4,26 +4,77 @@ use std::{
     convert::Infallible,
     net::SocketAddr,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
 };
 
 use eyre::Result;
-use future::try_join;
 use futures_util::future;
 use hyper::{
-    client::HttpConnector, http::uri::PathAndQuery, upgrade::Upgraded, Body, Client, Method,
-    Request, Response, StatusCode, Uri,
+    client::HttpConnector,
+    http::{
+        header::{HeaderName, HeaderValue, ALT_SVC, HOST},
+        uri::PathAndQuery,
+    },
+    upgrade::Upgraded,
+    Body, Client, Method, Request, Response, StatusCode, Uri,
 };
-use tokio::{net::TcpStream, task};
+use tokio::{io::AsyncWriteExt, net::TcpStream, task};
 use tower::{Service, ServiceBuilder};
 
 use super::log::{LogLayer, LogService};
-use crate::tls::TlsStream;
+use crate::{
+    cert, forward, http3, proxy_protocol,
+    settings::{self, ClientAuthMode, ClientAuths, Routes},
+    tls::{ClientCert, ConnInfo, TlsStream},
+};
 
 type ResponseFuture<T, E> = Pin<Box<dyn std::future::Future<Output = Result<T, E>> + Send>>;
 
+static CLIENT_SUBJECT: HeaderName = HeaderName::from_static("x-client-cert-subject");
+static CLIENT_SAN: HeaderName = HeaderName::from_static("x-client-cert-san");
+
 pub struct Svc {
     client: Client<HttpConnector>,
+    routes: Arc<Routes>,
+    fallback_upstream: Option<Arc<str>>,
+    client_auth: Arc<ClientAuths>,
+    proxy_protocol: Arc<settings::ProxyProtocol>,
+    conn: Arc<ConnInfo>,
+    remote_addr: SocketAddr,
+    local_addr: SocketAddr,
+    /// Advertisement of the HTTP/3 listener, for clients that didn't connect through it.
+    advertisement: Option<HeaderValue>,
+}
+
+impl Svc {
+    /// Client certificate of the connection, if it was verified for the request's host. The
+    /// handshake only verifies against the CA for the SNI name, so it has to match the host.
+    fn client_cert(&self, host: &str) -> Result<Option<&ClientCert>, StatusCode> {
+        let Some(auth) = cert::lookup(&self.client_auth, host) else {
+            return Ok(None);
+        };
+
+        let verified = self
+            .conn
+            .server_name
+            .as_deref()
+            .and_then(|name| cert::lookup(&self.client_auth, name))
+            .is_some_and(|sni_auth| sni_auth.ca == auth.ca);
+
+        match &self.conn.client_cert {
+            Some(cert) if verified => Ok(Some(cert)),
+            // QUIC handshakes don't request client certificates, so clients have to retry over a
+            // TLS connection, which the status tells them to.
+            _ if auth.mode == ClientAuthMode::Require
+                && self.conn.alpn_protocol.as_deref() == Some(http3::ALPN) =>
+            {
+                Err(StatusCode::MISDIRECTED_REQUEST)
+            }
+            _ if auth.mode == ClientAuthMode::Require => Err(StatusCode::FORBIDDEN),
+            _ => Ok(None),
+        }
+    }
 }
 
 impl Service<Request<Body>> for Svc {
@@ -38,22 +89,107 @@ impl Service<Request<Body>> for Svc {
     fn call(&mut self, mut req: Request<Body>) -> Self::Future {
         log::info!("{:?}", req);
 
+        // Clients that don't send a host, like HTTP/1.0 ones, are routed by their SNI name.
+        let host = request_host(&req).or_else(|| self.conn.server_name.clone());
+
+        // Never pass on client certificate headers that the client set itself.
+        req.headers_mut().remove(&CLIENT_SUBJECT);
+        req.headers_mut().remove(&CLIENT_SAN);
+
+        match host.as_deref().map(|host| self.client_cert(host)) {
+            Some(Ok(Some(cert))) => add_client_cert_headers(&mut req, cert),
+            Some(Err(status)) => {
+                let mut resp =
+                    Response::new(Body::from("a valid client certificate is required\n"));
+                *resp.status_mut() = status;
+
+                return Box::pin(future::ok(resp));
+            }
+            _ => {}
+        }
+
+        // Hosts with client certificates aren't advertised, as they can't be served over QUIC.
+        let alt_svc = self.advertisement.clone().filter(|_| {
+            host.as_deref()
+                .is_none_or(|host| cert::lookup(&self.client_auth, host).is_none())
+        });
+
+        let upstream = host
+            .and_then(|host| cert::lookup(&self.routes, &host).cloned())
+            .or_else(|| self.fallback_upstream.as_deref().map(str::to_owned));
+
+        let Some(upstream) = upstream else {
+            let mut resp = Response::new(Body::empty());
+            *resp.status_mut() = StatusCode::NOT_FOUND;
+
+            return Box::pin(future::ok(resp));
+        };
+
         let uri_string = format!(
-            "{}://localhost:1111{}",
+            "{}://{}{}",
             req.uri().scheme_str().unwrap_or("http"),
+            upstream,
             req.uri().path_and_query().map_or("/", PathAndQuery::as_str)
         );
         let uri = uri_string.parse().unwrap();
 
         *req.uri_mut() = uri;
 
-        Box::pin(proxy(self.client.clone(), req))
+        let header = self
+            .proxy_protocol
+            .upstreams
+            .get(&upstream)
+            .map(|&version| proxy_protocol::header(version, self.remote_addr, self.local_addr));
+
+        let resp = proxy(self.client.clone(), req, upstream, header);
+
+        Box::pin(async move {
+            let mut resp = resp.await?;
+            if let Some(alt_svc) = alt_svc {
+                resp.headers_mut().insert(ALT_SVC, alt_svc);
+            }
+
+            Ok(resp)
+        })
+    }
+}
+
+/// Extract the lowercased host name of a request, without the port, either from the request URI
+/// or the `Host` header.
+fn request_host<T>(req: &Request<T>) -> Option<String> {
+    req.uri()
+        .host()
+        .map(str::to_owned)
+        .or_else(|| {
+            req.headers()
+                .get(HOST)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<Uri>().ok())
+                .and_then(|uri| uri.host().map(str::to_owned))
+        })
+        .map(|host| host.to_ascii_lowercase())
+}
+
+fn add_client_cert_headers<T>(req: &mut Request<T>, cert: &ClientCert) {
+    let values = [
+        (&CLIENT_SUBJECT, cert.subject.clone()),
+        (&CLIENT_SAN, cert.sans.join(", ")),
+    ];
+
+    for (name, value) in values {
+        if let Ok(value) = HeaderValue::try_from(value) {
+            req.headers_mut().insert(name, value);
+        } else {
+            log::warn!("client certificate value for {} isn't a valid header", name);
+        }
     }
 }
 
 async fn proxy(
     client: Client<HttpConnector>,
     req: Request<Body>,
+    upstream: String,
+    header: Option<Vec<u8>>,
 ) -> Result<Response<Body>, hyper::Error> {
     if req.method() == Method::CONNECT {
         if let Some(addr) = host_addr(req.uri()) {
@@ -77,29 +213,62 @@ async fn proxy(
 
             Ok(resp)
         }
+    } else if let Some(header) = header {
+        request_with_header(req, &upstream, &header).await
     } else {
         client.request(req).await
     }
 }
 
+/// Send the request over a new connection that starts with a PROXY protocol header. These
+/// connections can't be pooled, as the header only applies to a single client.
+async fn request_with_header(
+    mut req: Request<Body>,
+    upstream: &str,
+    header: &[u8],
+) -> Result<Response<Body>, hyper::Error> {
+    let connect = async {
+        let mut stream = TcpStream::connect(upstream).await?;
+        stream.write_all(header).await?;
+        std::io::Result::Ok(stream)
+    };
+
+    let stream = match connect.await {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::warn!("failed connecting to {}: {}", upstream, e);
+
+            let mut resp = Response::new(Body::empty());
+            *resp.status_mut() = StatusCode::BAD_GATEWAY;
+
+            return Ok(resp);
+        }
+    };
+
+    let (mut sender, conn) = hyper::client::conn::handshake(stream).await?;
+    task::spawn(async move {
+        if let Err(e) = conn.await {
+            log::warn!("upstream connection error: {}", e);
+        }
+    });
+
+    // Unlike the pooled client, a plain connection sends the URI as it is.
+    *req.uri_mut() = req
+        .uri()
+        .path_and_query()
+        .map_or_else(|| Uri::from_static("/"), |pq| pq.as_str().parse().unwrap());
+
+    sender.send_request(req).await
+}
+
 fn host_addr(uri: &Uri) -> Option<SocketAddr> {
     uri.authority().and_then(|auth| auth.as_str().parse().ok())
 }
 
 async fn tunnel(upgraded: Upgraded, addr: SocketAddr) -> std::io::Result<()> {
-    let mut server = TcpStream::connect(addr).await?;
+    let server = TcpStream::connect(addr).await?;
 
-    let amounts = {
-        let (mut server_rx, mut server_tx) = server.split();
-        let (mut client_rx, mut client_tx) = tokio::io::split(upgraded);
-
-        let client_to_server = tokio::io::copy(&mut client_rx, &mut server_tx);
-        let server_to_client = tokio::io::copy(&mut server_rx, &mut client_tx);
-
-        try_join(client_to_server, server_to_client).await
-    };
-
-    match amounts {
+    match forward::copy(upgraded, server, None).await {
         Ok((from_client, from_server)) => log::info!(
             "client wrote {} bytes, received {} bytes",
             from_client,
@@ -111,13 +280,59 @@ async fn tunnel(upgraded: Upgraded, addr: SocketAddr) -> std::io::Result<()> {
     Ok(())
 }
 
+#[derive(Clone)]
 pub struct MakeSvc {
     client: Client<HttpConnector>,
+    routes: Arc<Routes>,
+    fallback_upstream: Option<Arc<str>>,
+    client_auth: Arc<ClientAuths>,
+    proxy_protocol: Arc<settings::ProxyProtocol>,
+    advertisement: Option<HeaderValue>,
 }
 
 impl MakeSvc {
-    pub const fn new(client: Client<HttpConnector>) -> Self {
-        Self { client }
+    /// Create a new service factory. The fallback upstream receives all requests for hosts that
+    /// aren't part of the routes, like on-demand certificate hosts.
+    pub fn new(
+        client: Client<HttpConnector>,
+        routes: Arc<Routes>,
+        fallback_upstream: Option<String>,
+        client_auth: Arc<ClientAuths>,
+        proxy_protocol: Arc<settings::ProxyProtocol>,
+        http3: Option<&settings::Http3>,
+    ) -> Self {
+        Self {
+            client,
+            routes,
+            fallback_upstream: fallback_upstream.map(Into::into),
+            client_auth,
+            proxy_protocol,
+            advertisement: http3.and_then(|http3| HeaderValue::try_from(http3.alt_svc()).ok()),
+        }
+    }
+
+    /// Service for the requests of a single connection.
+    pub fn service(
+        &self,
+        conn: Arc<ConnInfo>,
+        remote_addr: SocketAddr,
+        local_addr: SocketAddr,
+    ) -> LogService<Svc> {
+        let quic = conn.alpn_protocol.as_deref() == Some(http3::ALPN);
+
+        ServiceBuilder::new()
+            .layer(LogLayer::new(remote_addr))
+            .service(Svc {
+                client: self.client.clone(),
+                routes: self.routes.clone(),
+                fallback_upstream: self.fallback_upstream.clone(),
+                client_auth: self.client_auth.clone(),
+                proxy_protocol: self.proxy_protocol.clone(),
+                conn,
+                remote_addr,
+                local_addr,
+                advertisement: self.advertisement.clone().filter(|_| !quic),
+            })
     }
 }
 
@@ -131,12 +346,96 @@ impl Service<&TlsStream> for MakeSvc {
     }
 
     fn call(&mut self, conn: &TlsStream) -> Self::Future {
-        future::ok(
-            ServiceBuilder::new()
-                .layer(LogLayer::new(conn.remote_addr))
-                .service(Svc {
-                    client: self.client.clone(),
-                }),
-        )
+        log::debug!(
+            "{} connected with {} for {} (ALPN {}, client certificate {})",
+            conn.remote_addr,
+            conn.info.version,
+            conn.info.server_name.as_deref().unwrap_or("-"),
+            conn.info.alpn_protocol.as_deref().unwrap_or("-"),
+            conn.info
+                .client_cert
+                .as_ref()
+                .map_or("-", |cert| cert.subject.as_str()),
+        );
+
+        future::ok(self.service(conn.info.clone(), conn.remote_addr, conn.local_addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn svc(server_name: &str, client_cert: Option<ClientCert>, mode: ClientAuthMode) -> Svc {
+        let mut client_auth = ClientAuths::default();
+        client_auth.insert(
+            "admin.sample.com".to_owned(),
+            settings::ClientAuth {
+                ca: "clients-ca.pem".into(),
+                mode,
+            },
+        );
+
+        Svc {
+            client: Client::new(),
+            routes: Arc::default(),
+            fallback_upstream: None,
+            client_auth: Arc::new(client_auth),
+            proxy_protocol: Arc::default(),
+            conn: Arc::new(ConnInfo {
+                server_name: Some(server_name.to_owned()),
+                alpn_protocol: None,
+                version: "TLSv1.3",
+                client_cert,
+            }),
+            remote_addr: ([127, 0, 0, 1], 50000).into(),
+            local_addr: ([127, 0, 0, 1], 8443).into(),
+            advertisement: None,
+        }
+    }
+
+    fn cert() -> ClientCert {
+        ClientCert {
+            subject: "CN=admin".to_owned(),
+            sans: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn require_rejects_missing_certificate() {
+        let svc = svc("admin.sample.com", None, ClientAuthMode::Require);
+
+        assert_eq!(
+            Some(StatusCode::FORBIDDEN),
+            svc.client_cert("admin.sample.com").err()
+        );
+        assert!(matches!(svc.client_cert("www.sample.com"), Ok(None)));
+    }
+
+    #[test]
+    fn require_rejects_certificate_verified_for_other_host() {
+        let svc = svc("www.sample.com", Some(cert()), ClientAuthMode::Require);
+
+        assert_eq!(
+            Some(StatusCode::FORBIDDEN),
+            svc.client_cert("admin.sample.com").err()
+        );
+    }
+
+    #[test]
+    fn verified_certificate_passed_on() {
+        let svc = svc("admin.sample.com", Some(cert()), ClientAuthMode::Require);
+
+        assert!(matches!(
+            svc.client_cert("admin.sample.com"),
+            Ok(Some(cert)) if cert.subject == "CN=admin"
+        ));
+    }
+
+    #[test]
+    fn optional_allows_missing_certificate() {
+        let svc = svc("admin.sample.com", None, ClientAuthMode::Optional);
+
+        assert!(matches!(svc.client_cert("admin.sample.com"), Ok(None)));
     }
 }
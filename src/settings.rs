@@ -1,22 +1,743 @@
 //! Global application settings.
 
-use std::{collections::HashMap, fs};
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
 
 use ahash::RandomState;
-use eyre::Result;
+use eyre::{bail, ensure, Result};
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub acme: Acme,
-    pub routes: HashMap<String, String, RandomState>,
+    pub routes: Routes,
+    /// Hosts whose TLS connections are forwarded to the upstream without terminating TLS, which
+    /// has to be done by the upstream instead. Matched against the SNI name like the routes.
+    #[serde(default)]
+    pub passthrough: Routes,
+    #[serde(default)]
+    pub tcp: Vec<Tcp>,
+    #[serde(default)]
+    pub udp: Vec<Udp>,
+    #[serde(default)]
+    pub certificates: Vec<Certificate>,
+    #[serde(default)]
+    pub fallback: Fallback,
+    pub on_demand: Option<OnDemand>,
+    #[serde(default)]
+    pub ocsp: Ocsp,
+    #[serde(default)]
+    pub storage: Storage,
+    /// Client certificate authentication for the routes matching the host names, either exact or
+    /// wildcards in the form of `*.example.com`.
+    #[serde(default)]
+    pub client_auth: ClientAuths,
+    #[serde(default)]
+    pub tls: Tls,
+    pub http3: Option<Http3>,
+    #[serde(default)]
+    pub sessions: Sessions,
+    #[serde(default)]
+    pub proxy_protocol: ProxyProtocol,
+    #[serde(default)]
+    pub shutdown: Shutdown,
+    pub handoff: Option<Handoff>,
 }
 
-#[derive(Debug, Deserialize)]
+impl Settings {
+    /// Groups of host names that are each covered by a single ACME certificate. Explicitly
+    /// configured groups come first and every route that isn't part of any group gets its own
+    /// certificate. Groups with static certificate files are skipped.
+    pub fn acme_groups(&self) -> Vec<Certificate> {
+        let mut groups = self
+            .certificates
+            .iter()
+            .filter(|c| c.files().is_none())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for host in self.routes.keys() {
+            if !self.certificates.iter().any(|c| c.domains.contains(host)) {
+                groups.push(Certificate {
+                    domains: vec![host.clone()],
+                    key_types: default_key_types(),
+                    cert: None,
+                    key: None,
+                });
+            }
+        }
+
+        groups
+    }
+
+    /// Groups of host names that use user-provided certificate files instead of ACME.
+    pub fn static_groups(&self) -> Vec<Certificate> {
+        self.certificates
+            .iter()
+            .filter(|c| c.files().is_some())
+            .cloned()
+            .collect()
+    }
+}
+
+/// Mapping from host names to upstream addresses. Keys can be exact names or wildcards in the form
+/// of `*.example.com`, covering a single additional label.
+pub type Routes = HashMap<String, String, RandomState>;
+
+/// Listener that forwards TCP connections to an upstream as they are, for protocols other than
+/// HTTP.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Tcp {
+    pub listen: SocketAddr,
+    pub upstream: String,
+    /// Seconds without traffic in either direction, after which a connection is closed.
+    #[serde(default = "default_tcp_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Maximum amount of concurrent connections, any further ones are closed right away.
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    /// Expect a PROXY protocol header from trusted sources.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+}
+
+const fn default_tcp_idle_timeout() -> u64 {
+    300
+}
+
+/// Listener that forwards UDP datagrams to an upstream. Every client address gets its own session
+/// with a separate socket to the upstream, so replies can be sent back to the right client.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Udp {
+    pub listen: SocketAddr,
+    /// Host name or address of the upstream, which is resolved once on startup.
+    pub upstream: String,
+    /// Seconds without datagrams in either direction, after which a session is closed.
+    #[serde(default = "default_udp_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Maximum amount of concurrent sessions, datagrams of any further clients are dropped.
+    #[serde(default = "default_max_connections")]
+    pub max_sessions: usize,
+}
+
+const fn default_udp_idle_timeout() -> u64 {
+    60
+}
+
+const fn default_max_connections() -> usize {
+    1024
+}
+
+#[derive(Clone, Debug, Deserialize)]
 pub struct Acme {
     pub email: String,
+    /// Directory URL of the ACME server.
+    #[serde(default = "default_directory")]
+    pub directory: String,
+    /// External account binding, which some CAs require to link the ACME account to an existing
+    /// customer account.
+    pub eab: Option<Eab>,
+    /// Command that is called to create and remove DNS `TXT` records for DNS-01 challenges, which
+    /// are required for wildcard certificates. It is invoked as `<cmd> present <record> <proof>`
+    /// and `<cmd> cleanup <record> <proof>`.
+    pub dns_hook: Option<String>,
+    /// Seconds to wait after the DNS hook created a record, before the CA is asked to validate
+    /// it, so the record can propagate to all authoritative name servers.
+    #[serde(default = "default_dns_propagation_delay")]
+    pub dns_propagation_delay: u64,
+}
+
+fn default_directory() -> String {
+    "https://acme-staging-v02.api.letsencrypt.org/directory".to_owned()
+}
+
+const fn default_dns_propagation_delay() -> u64 {
+    30
+}
+
+/// Credentials for external account binding, as provided by the CA.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Eab {
+    pub key_id: String,
+    /// Base64url encoded HMAC key.
+    pub hmac_key: String,
+}
+
+/// Group of host names that share one certificate, with the first name being the primary name and
+/// all others being added as alternative names, to stay within the CA's rate limits.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Certificate {
+    pub domains: Vec<String>,
+    /// Key types to request certificates for. Configuring both an ECDSA and an RSA key type allows
+    /// to serve old clients that don't support ECDSA, while everyone else gets the ECDSA one.
+    #[serde(default = "default_key_types")]
+    pub key_types: Vec<KeyType>,
+    /// Path to an existing certificate chain in PEM format, for example from a corporate CA. If
+    /// set, no certificate is requested through ACME and the file is reloaded on changes.
+    pub cert: Option<PathBuf>,
+    /// Path to the private key in PEM format, belonging to the static [`Self::cert`].
+    pub key: Option<PathBuf>,
+}
+
+impl Certificate {
+    /// Certificate and key files, if this is a user-provided static certificate.
+    pub fn files(&self) -> Option<(&Path, &Path)> {
+        self.cert.as_deref().zip(self.key.as_deref())
+    }
+}
+
+fn default_key_types() -> Vec<KeyType> {
+    vec![KeyType::default()]
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyType {
+    EcdsaP256,
+    #[default]
+    EcdsaP384,
+    Rsa2048,
+    Rsa4096,
+}
+
+impl KeyType {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::EcdsaP256 => "ecdsa-p256",
+            Self::EcdsaP384 => "ecdsa-p384",
+            Self::Rsa2048 => "rsa2048",
+            Self::Rsa4096 => "rsa4096",
+        }
+    }
+}
+
+/// Certificate to use for handshakes with an unknown or missing SNI name.
+#[derive(Debug, Default, Deserialize)]
+#[serde(tag = "mode", rename_all = "kebab-case")]
+pub enum Fallback {
+    /// Serve an in-memory self-signed certificate, which is also used for configured domains that
+    /// don't have a certificate yet.
+    #[default]
+    SelfSigned,
+    /// Serve the given certificate and key files.
+    Static { cert: PathBuf, key: PathBuf },
+    /// Abort the handshake.
+    Reject,
+}
+
+/// On-demand certificates, that are requested on the first handshake for a host that isn't
+/// configured in the routes. A host is only allowed if it matches one of the `allow` patterns or
+/// the `ask` endpoint confirms it.
+#[derive(Debug, Deserialize)]
+pub struct OnDemand {
+    /// Upstream that serves all hosts with on-demand certificates.
+    pub upstream: String,
+    /// Host names, either exact or wildcards in the form of `*.example.com`.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// HTTP endpoint that is queried with `?domain=<host>` and must reply with a success status
+    /// code to allow the host.
+    pub ask: Option<String>,
+    /// Maximum amount of certificate orders within an hour.
+    #[serde(default = "default_max_orders")]
+    pub max_orders_per_hour: usize,
+    /// Seconds to wait before retrying a host that was denied or whose order failed.
+    #[serde(default = "default_failure_ttl")]
+    pub failure_ttl: u64,
+}
+
+const fn default_max_orders() -> usize {
+    10
+}
+
+const fn default_failure_ttl() -> u64 {
+    3600
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Ocsp {
+    /// Staple OCSP responses to all certificates that have an OCSP responder.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Responder URL that overrides the one from the certificates, for example a local responder
+    /// for testing.
+    pub responder: Option<String>,
+}
+
+impl Default for Ocsp {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            responder: None,
+        }
+    }
+}
+
+const fn default_true() -> bool {
+    true
+}
+
+pub type ClientAuths = HashMap<String, ClientAuth, RandomState>;
+
+#[derive(Debug, Deserialize)]
+pub struct ClientAuth {
+    /// CA certificates in PEM format that client certificates are verified against.
+    pub ca: PathBuf,
+    #[serde(default)]
+    pub mode: ClientAuthMode,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientAuthMode {
+    /// Deny requests without a valid client certificate.
+    #[default]
+    Require,
+    /// Request a client certificate, but also allow clients without one.
+    Optional,
+}
+
+/// TLS policy for all hosts, with overrides for the ones matching the host names in `hosts`,
+/// either exact or wildcards in the form of `*.example.com`.
+#[derive(Debug, Deserialize)]
+pub struct Tls {
+    #[serde(flatten)]
+    pub policy: TlsPolicy,
+    #[serde(default)]
+    pub hosts: HashMap<String, TlsPolicy, RandomState>,
+    /// Seconds a client has to complete the handshake before the connection is closed.
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout: u64,
+}
+
+impl Default for Tls {
+    fn default() -> Self {
+        Self {
+            policy: TlsPolicy::default(),
+            hosts: HashMap::default(),
+            handshake_timeout: default_handshake_timeout(),
+        }
+    }
+}
+
+const fn default_handshake_timeout() -> u64 {
+    10
+}
+
+/// Allowed protocol versions, cipher suites and key exchange groups. Explicit lists take
+/// precedence over the profile.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TlsPolicy {
+    pub profile: Option<TlsProfile>,
+    pub versions: Option<Vec<TlsVersion>>,
+    /// Cipher suite names as defined by IANA, like `TLS13_AES_256_GCM_SHA384` or
+    /// `TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384`.
+    pub cipher_suites: Option<Vec<String>>,
+    /// Key exchange groups, out of `x25519`, `secp256r1` and `secp384r1`.
+    pub kx_groups: Option<Vec<String>>,
+}
+
+impl TlsPolicy {
+    /// Apply a host's overrides. A profile resets everything, otherwise only the explicitly
+    /// given lists are replaced.
+    pub fn merge(&self, host: &Self) -> Self {
+        let base = if host.profile.is_some() {
+            Self::default()
+        } else {
+            self.clone()
+        };
+
+        Self {
+            profile: host.profile.or(base.profile),
+            versions: host.versions.clone().or(base.versions),
+            cipher_suites: host.cipher_suites.clone().or(base.cipher_suites),
+            kx_groups: host.kx_groups.clone().or(base.kx_groups),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsProfile {
+    /// Only TLS 1.3, like Mozilla's profile of the same name.
+    Modern,
+    /// TLS 1.2 and 1.3 with forward secret AEAD cipher suites.
+    #[default]
+    Intermediate,
+}
+
+impl TlsProfile {
+    pub fn versions(self) -> Vec<TlsVersion> {
+        match self {
+            Self::Modern => vec![TlsVersion::Tls13],
+            Self::Intermediate => vec![TlsVersion::Tls12, TlsVersion::Tls13],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// HTTP/3 over QUIC, which serves the routes with the same certificates as HTTPS and is
+/// advertised to HTTPS clients through `Alt-Svc` headers.
+#[derive(Debug, Deserialize)]
+pub struct Http3 {
+    #[serde(default = "default_http3_listen")]
+    pub listen: SocketAddr,
+    /// Port to advertise, if clients reach the listener on a different one than it listens on,
+    /// like through port forwarding.
+    pub advertised_port: Option<u16>,
+    /// Seconds clients remember the advertisement.
+    #[serde(default = "default_alt_svc_max_age")]
+    pub max_age: u64,
+}
+
+impl Http3 {
+    /// `Alt-Svc` header value that points clients to the listener.
+    pub fn alt_svc(&self) -> String {
+        format!(
+            "h3=\":{}\"; ma={}",
+            self.advertised_port.unwrap_or_else(|| self.listen.port()),
+            self.max_age
+        )
+    }
+}
+
+fn default_http3_listen() -> SocketAddr {
+    ([0, 0, 0, 0], 8443).into()
+}
+
+const fn default_alt_svc_max_age() -> u64 {
+    24 * 3600
+}
+
+/// TLS session resumption, through tickets and a server-side session cache.
+#[derive(Debug, Deserialize)]
+pub struct Sessions {
+    /// Issue session tickets, which let clients resume sessions without any server-side state.
+    #[serde(default = "default_true")]
+    pub tickets: bool,
+    /// Seconds after which a new ticket key is used. Tickets stay valid for one more rotation.
+    #[serde(default = "default_ticket_rotation")]
+    pub ticket_rotation: u64,
+    /// File with the ticket keys, shared by several instances so they can resume each other's
+    /// sessions. Whichever instance notices first that the keys are due rotates them, holding the
+    /// storage lock.
+    pub ticket_key_file: Option<PathBuf>,
+    /// Maximum amount of sessions in the server-side cache, or 0 to disable it.
+    #[serde(default = "default_cache_size")]
+    pub cache_size: usize,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self {
+            tickets: true,
+            ticket_rotation: default_ticket_rotation(),
+            ticket_key_file: None,
+            cache_size: default_cache_size(),
+        }
+    }
+}
+
+const fn default_ticket_rotation() -> u64 {
+    6 * 3600
+}
+
+const fn default_cache_size() -> usize {
+    256
+}
+
+/// Graceful shutdown on SIGINT or SIGTERM, which stops accepting new connections and waits for the
+/// active ones to finish.
+#[derive(Debug, Deserialize)]
+pub struct Shutdown {
+    /// Seconds to wait for active requests and tunnels, before the remaining connections are
+    /// closed forcibly.
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            drain_timeout: default_drain_timeout(),
+        }
+    }
+}
+
+const fn default_drain_timeout() -> u64 {
+    30
+}
+
+/// Handoff of the listening sockets to a new process, which takes them over from the running one
+/// when started with the same settings, so upgrades don't refuse any connections. The HTTP/3
+/// socket is left out, as QUIC connections can't move between processes: the new process binds
+/// it once the running one drained its connections, and until then clients fall back to HTTPS.
+#[derive(Debug, Deserialize)]
+pub struct Handoff {
+    /// Unix socket that the running process listens on for the next one.
+    pub socket: PathBuf,
+}
+
+/// PROXY protocol headers, which load balancers in front of charon use to pass on the original
+/// client address.
+#[derive(Debug, Default, Deserialize)]
+pub struct ProxyProtocol {
+    /// Addresses of the load balancers, either single IPs or ranges in CIDR notation. Only their
+    /// connections are expected to start with a header, others are taken as they are.
+    #[serde(default)]
+    pub trusted: Vec<IpRange>,
+    /// Expect headers on the HTTPS listener.
+    #[serde(default)]
+    pub https: bool,
+    /// Expect headers on the HTTP listener.
+    #[serde(default)]
+    pub http: bool,
+    /// Upstreams that get a header with the client address at the start of every connection, for
+    /// routes, passthrough hosts and TCP listeners alike.
+    #[serde(default)]
+    pub upstreams: HashMap<String, ProxyProtocolVersion, RandomState>,
+}
+
+impl ProxyProtocol {
+    pub fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|range| range.contains(ip))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProxyProtocolVersion {
+    /// Human-readable text format.
+    V1,
+    /// Binary format.
+    V2,
+}
+
+/// Single IP address or range of addresses in CIDR notation, like `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (net, ip, bits) = match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net).into(), u32::from(ip).into(), 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false,
+        };
+
+        let shift = bits - self.prefix;
+        net.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (addr, prefix) = value
+            .split_once('/')
+            .map_or((value.as_str(), None), |(addr, prefix)| {
+                (addr, Some(prefix))
+            });
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|e| format!("invalid address `{value}`: {e}"))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix
+            .map_or(Ok(bits), str::parse)
+            .map_err(|e| format!("invalid prefix in `{value}`: {e}"))?;
+
+        if prefix > bits {
+            return Err(format!("prefix of `{value}` is longer than the address"));
+        }
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Where ACME accounts, certificates and pending challenges are kept.
+#[derive(Debug, Default, Deserialize)]
+#[serde(tag = "backend", rename_all = "kebab-case")]
+pub enum Storage {
+    /// Local `temp` directory, with challenges only kept in memory.
+    #[default]
+    Local,
+    /// Directory on a filesystem that is shared by several instances. Challenges are stored there
+    /// too, so every instance can answer them, and lock files make sure only one instance orders
+    /// a certificate at a time.
+    Shared { path: PathBuf },
 }
 
 pub fn load() -> Result<Settings> {
-    basic_toml::from_slice(&fs::read("config.toml")?).map_err(Into::into)
+    let settings = basic_toml::from_slice::<Settings>(&fs::read("config.toml")?)?;
+
+    for cert in &settings.certificates {
+        ensure!(
+            !cert.domains.is_empty(),
+            "certificate groups must contain at least one domain"
+        );
+        ensure!(
+            !cert.key_types.is_empty(),
+            "certificate groups must contain at least one key type"
+        );
+        ensure!(
+            cert.cert.is_some() == cert.key.is_some(),
+            "static certificates require both a certificate and a key file"
+        );
+    }
+
+    if let Some(host) = settings
+        .passthrough
+        .keys()
+        .find(|host| settings.routes.contains_key(*host))
+    {
+        bail!("{host} can't be both a route and a passthrough host");
+    }
+
+    ensure!(
+        settings.sessions.ticket_rotation > 0,
+        "the session ticket rotation must be at least one second"
+    );
+
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(value: &str) -> IpRange {
+        IpRange::try_from(value.to_owned()).unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn ip_range_v4() {
+        let range = range("10.1.0.0/16");
+
+        assert!(range.contains(ip("10.1.0.0")));
+        assert!(range.contains(ip("10.1.255.255")));
+        assert!(!range.contains(ip("10.2.0.1")));
+        assert!(!range.contains(ip("fd00::1")));
+    }
+
+    #[test]
+    fn ip_range_v6() {
+        let range = range("fd00:1::/32");
+
+        assert!(range.contains(ip("fd00:1::1")));
+        assert!(range.contains(ip("fd00:1:ffff::1")));
+        assert!(!range.contains(ip("fd00:2::1")));
+        assert!(!range.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn ip_range_single_address() {
+        let v4 = range("192.168.1.10");
+        assert!(v4.contains(ip("192.168.1.10")));
+        assert!(!v4.contains(ip("192.168.1.11")));
+
+        let v6 = range("::1");
+        assert!(v6.contains(ip("::1")));
+        assert!(!v6.contains(ip("::2")));
+    }
+
+    #[test]
+    fn ip_range_matches_mapped_v4() {
+        assert!(range("10.0.0.0/8").contains(ip("::ffff:10.0.0.1")));
+    }
+
+    #[test]
+    fn ip_range_zero_prefix_matches_all() {
+        assert!(range("0.0.0.0/0").contains(ip("203.0.113.1")));
+        assert!(range("::/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn ip_range_invalid() {
+        assert!(IpRange::try_from("10.0.0.0/33".to_owned()).is_err());
+        assert!(IpRange::try_from("::/129".to_owned()).is_err());
+        assert!(IpRange::try_from("10.0.0.0/x".to_owned()).is_err());
+        assert!(IpRange::try_from("10.0.0/8".to_owned()).is_err());
+    }
+
+    fn policy(toml: &str) -> TlsPolicy {
+        basic_toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn merge_replaces_given_lists() {
+        let global = policy(
+            r#"
+            versions = ["1.2", "1.3"]
+            kx_groups = ["x25519", "secp384r1"]
+            "#,
+        );
+        let merged = global.merge(&policy(r#"versions = ["1.3"]"#));
+
+        assert_eq!(Some(vec![TlsVersion::Tls13]), merged.versions);
+        assert_eq!(
+            Some(vec!["x25519".to_owned(), "secp384r1".to_owned()]),
+            merged.kx_groups
+        );
+        assert!(merged.profile.is_none());
+        assert!(merged.cipher_suites.is_none());
+    }
+
+    #[test]
+    fn merge_profile_resets_everything() {
+        let global = policy(
+            r#"
+            profile = "intermediate"
+            versions = ["1.2", "1.3"]
+            cipher_suites = ["TLS13_AES_256_GCM_SHA384"]
+            "#,
+        );
+        let merged = global.merge(&policy(
+            r#"
+            profile = "modern"
+            kx_groups = ["x25519"]
+            "#,
+        ));
+
+        assert_eq!(Some(TlsProfile::Modern), merged.profile);
+        assert_eq!(Some(vec!["x25519".to_owned()]), merged.kx_groups);
+        assert!(merged.versions.is_none());
+        assert!(merged.cipher_suites.is_none());
+    }
+
+    #[test]
+    fn merge_without_overrides_keeps_global() {
+        let global = policy(
+            r#"
+            profile = "modern"
+            cipher_suites = ["TLS13_CHACHA20_POLY1305_SHA256"]
+            "#,
+        );
+        let merged = global.merge(&TlsPolicy::default());
+
+        assert_eq!(Some(TlsProfile::Modern), merged.profile);
+        assert_eq!(
+            Some(vec!["TLS13_CHACHA20_POLY1305_SHA256".to_owned()]),
+            merged.cipher_suites
+        );
+    }
 }
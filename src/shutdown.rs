@@ -0,0 +1,162 @@
+//! Graceful shutdown, that stops accepting new connections and waits for the active ones to
+//! finish before exiting.
+
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::{info, warn};
+use tokio::sync::{watch, Notify};
+
+#[derive(Clone)]
+pub struct Shutdown {
+    trigger: Arc<watch::Sender<bool>>,
+    active: Arc<AtomicUsize>,
+    idle: Arc<Notify>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            trigger: Arc::new(watch::channel(false).0),
+            active: Arc::default(),
+            idle: Arc::default(),
+        }
+    }
+
+    /// Trigger the shutdown once the process receives SIGINT or SIGTERM.
+    pub async fn listen(self) {
+        wait_for_signal().await;
+        info!("received shutdown signal");
+        self.trigger();
+    }
+
+    pub fn trigger(&self) {
+        self.trigger.send_replace(true);
+    }
+
+    /// Complete once the shutdown was triggered.
+    pub fn signaled(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut signal = self.trigger.subscribe();
+
+        async move {
+            while !*signal.borrow_and_update() {
+                if signal.changed().await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Count the connection as active, until the returned guard is dropped.
+    pub fn track(&self) -> Guard {
+        self.active.fetch_add(1, Ordering::SeqCst);
+
+        Guard {
+            active: self.active.clone(),
+            idle: self.idle.clone(),
+        }
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Complete once there are no active connections left.
+    pub async fn idle(&self) {
+        loop {
+            // Registered before checking, to not miss the last connection closing in between.
+            let idle = self.idle.notified();
+            if self.active() == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    /// Run the servers until they stop by themselves or the shutdown is triggered. Then wait for
+    /// them to finish their requests and for the remaining connections, up to the timeout. The
+    /// servers stop accepting right away, but they don't track connections that were upgraded to
+    /// tunnels, nor passthroughs and TCP forwards, so those are waited for separately. Nothing is
+    /// returned if the timeout is reached.
+    pub async fn serve<F: Future>(&self, servers: F, timeout: Duration) -> Option<F::Output> {
+        tokio::pin!(servers);
+
+        tokio::select! {
+            ret = &mut servers => return Some(ret),
+            () = self.signaled() => {}
+        }
+
+        let active = self.active();
+        info!(
+            "shutting down, waiting up to {:?} for {} active connections",
+            timeout, active
+        );
+
+        let drain = async {
+            let ret = servers.await;
+            self.idle().await;
+            ret
+        };
+        let drained = tokio::time::timeout(timeout, drain).await.ok();
+
+        if drained.is_some() {
+            info!("drained all {} connections", active);
+        } else {
+            let remaining = self.active();
+            warn!(
+                "drained {} connections, closing the remaining {} forcibly",
+                active.saturating_sub(remaining),
+                remaining
+            );
+        }
+
+        drained
+    }
+}
+
+/// Active connection, that counts as finished once dropped.
+pub struct Guard {
+    active: Arc<AtomicUsize>,
+    idle: Arc<Notify>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if self.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!("failed listening for SIGTERM: {}", e);
+            tokio::signal::ctrl_c().await.ok();
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        warn!("failed listening for shutdown signal: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
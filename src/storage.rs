@@ -0,0 +1,403 @@
+//! Storage for ACME accounts, certificates and pending challenges, either local to this instance
+//! or shared with other instances through a common directory.
+
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use acme_lib::persist::{Persist, PersistKey, PersistKind};
+use ahash::RandomState;
+use eyre::Result;
+use log::warn;
+use parking_lot::RwLock;
+
+use crate::settings;
+
+const LOCAL_DIR: &str = "temp";
+/// Host names that got an on-demand certificate, one per line.
+const ON_DEMAND_FILE: &str = "on-demand-hosts";
+/// Interval to check whether another instance released a lock.
+const LOCK_POLL: Duration = Duration::from_secs(5);
+/// Age after which a lock is considered abandoned by a crashed instance.
+const LOCK_TIMEOUT: Duration = Duration::from_mins(15);
+/// Interval to update the modification time of held locks, so they're never taken for abandoned.
+const LOCK_REFRESH: Duration = Duration::from_mins(1);
+
+#[derive(Clone)]
+pub struct Storage {
+    dir: PathBuf,
+    challenges: Challenges,
+}
+
+/// Pending HTTP-01 challenges, mapping tokens to their key authorization. Tokens are unique, so
+/// several orders, even for the same host, can be validated at the same time.
+#[derive(Clone)]
+enum Challenges {
+    Memory(Arc<RwLock<HashMap<String, String, RandomState>>>),
+    /// One file per token, so every instance can answer the CA's validation requests.
+    Shared(PathBuf),
+}
+
+impl Storage {
+    pub fn new(settings: &settings::Storage) -> Result<Self> {
+        let storage = match settings {
+            settings::Storage::Local => Self {
+                dir: LOCAL_DIR.into(),
+                challenges: Challenges::Memory(Arc::default()),
+            },
+            settings::Storage::Shared { path } => {
+                fs::create_dir_all(path.join("challenges"))?;
+                fs::create_dir_all(path.join("locks"))?;
+
+                Self {
+                    dir: path.clone(),
+                    challenges: Challenges::Shared(path.join("challenges")),
+                }
+            }
+        };
+
+        fs::create_dir_all(&storage.dir)?;
+
+        Ok(storage)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub const fn is_shared(&self) -> bool {
+        matches!(self.challenges, Challenges::Shared(_))
+    }
+
+    pub async fn challenge(&self, token: &str) -> Option<String> {
+        match &self.challenges {
+            Challenges::Memory(map) => map.read().get(token).cloned(),
+            Challenges::Shared(dir) => {
+                // Tokens come straight from the request path, so anything that isn't base64url
+                // is rejected before touching the filesystem.
+                let valid = !token.is_empty()
+                    && token
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+
+                if !valid {
+                    return None;
+                }
+
+                let path = dir.join(token);
+                tokio::task::spawn_blocking(move || fs::read_to_string(path).ok())
+                    .await
+                    .ok()
+                    .flatten()
+            }
+        }
+    }
+
+    pub fn add_challenge(&self, token: &str, proof: &str) -> Result<()> {
+        match &self.challenges {
+            Challenges::Memory(map) => {
+                map.write().insert(token.to_owned(), proof.to_owned());
+                Ok(())
+            }
+            Challenges::Shared(dir) => write_atomic(&dir.join(token), proof.as_bytes(), false),
+        }
+    }
+
+    pub fn remove_challenge(&self, token: &str) {
+        match &self.challenges {
+            Challenges::Memory(map) => {
+                map.write().remove(token);
+            }
+            Challenges::Shared(dir) => {
+                if let Err(e) = fs::remove_file(dir.join(token)) {
+                    warn!("failed removing challenge {}: {}", token, e);
+                }
+            }
+        }
+    }
+
+    /// Host names that got an on-demand certificate, so they're loaded and renewed like configured
+    /// ones after a restart.
+    pub fn on_demand_hosts(&self) -> Result<Vec<String>> {
+        match fs::read_to_string(self.dir.join(ON_DEMAND_FILE)) {
+            Ok(hosts) => Ok(hosts.lines().map(ToOwned::to_owned).collect()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn add_on_demand_host(&self, host: &str) -> Result<()> {
+        let _lock = self.lock(ON_DEMAND_FILE)?;
+
+        let mut hosts = self.on_demand_hosts()?;
+        if hosts.iter().any(|h| h == host) {
+            return Ok(());
+        }
+
+        hosts.push(host.to_owned());
+        write_atomic(
+            &self.dir.join(ON_DEMAND_FILE),
+            format!("{}\n", hosts.join("\n")).as_bytes(),
+            false,
+        )
+    }
+
+    pub fn remove(&self, key: &PersistKey<'_>) -> Result<()> {
+        fs::remove_file(self.path(key)).map_err(Into::into)
+    }
+
+    /// Take the lock with the given name, waiting for another instance to release it first. For
+    /// local storage, this returns right away as no other instance uses it.
+    pub fn lock(&self, name: &str) -> Result<Lock> {
+        if !self.is_shared() {
+            return Ok(Lock {
+                held: None,
+                waited: false,
+            });
+        }
+
+        let path = self.dir.join("locks").join(format!("{name}.lock"));
+        let mut waited = false;
+
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    write!(file, "{}", process::id())?;
+                    return Ok(Lock {
+                        held: Some(Held::new(path)?),
+                        waited,
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    if is_abandoned(&path) {
+                        take_over(&path);
+                    } else {
+                        waited = true;
+                        thread::sleep(LOCK_POLL);
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Same file naming as acme-lib's `FilePersist`, so existing data stays readable.
+    fn path(&self, key: &PersistKey<'_>) -> PathBuf {
+        let ext = match key.kind {
+            PersistKind::Certificate => "crt",
+            PersistKind::PrivateKey | PersistKind::AccountPrivateKey => "key",
+        };
+
+        self.dir.join(key.to_string()).with_extension(ext)
+    }
+}
+
+impl Persist for Storage {
+    fn put(&self, key: &PersistKey<'_>, value: &[u8]) -> acme_lib::Result<()> {
+        let private = key.kind != PersistKind::Certificate;
+        write_atomic(&self.path(key), value, private).map_err(|e| e.to_string().into())
+    }
+
+    fn get(&self, key: &PersistKey<'_>) -> acme_lib::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(key)) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn is_abandoned(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age > LOCK_TIMEOUT)
+}
+
+/// Remove an abandoned lock. It's moved to a unique name first, so of several instances that
+/// noticed it at the same time, only one removes it. If that instance moved a lock that another
+/// one took in the meantime instead, it's moved back, unless the name is taken again by now.
+fn take_over(path: &Path) {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let unique = path.with_extension(format!("stale{}-{nanos}", process::id()));
+
+    if fs::rename(path, &unique).is_err() {
+        return;
+    }
+
+    if is_abandoned(&unique) {
+        warn!("removing abandoned lock {}", path.display());
+    } else {
+        fs::hard_link(&unique, path).ok();
+    }
+
+    fs::remove_file(&unique).ok();
+}
+
+/// Lock on a shared resource, released when dropped.
+pub struct Lock {
+    held: Option<Held>,
+    waited: bool,
+}
+
+/// Lock file of shared storage, with a thread that keeps its modification time current.
+struct Held {
+    path: PathBuf,
+    stop: Option<mpsc::Sender<()>>,
+    refresher: Option<JoinHandle<()>>,
+}
+
+impl Held {
+    fn new(path: PathBuf) -> Result<Self> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let refresh_path = path.clone();
+
+        let refresher = thread::Builder::new()
+            .name("lock-refresh".to_owned())
+            .spawn(move || {
+                while stopped.recv_timeout(LOCK_REFRESH) == Err(RecvTimeoutError::Timeout) {
+                    let result = OpenOptions::new()
+                        .write(true)
+                        .open(&refresh_path)
+                        .and_then(|file| file.set_modified(SystemTime::now()));
+
+                    if let Err(e) = result {
+                        warn!("failed refreshing lock {}: {}", refresh_path.display(), e);
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            path,
+            stop: Some(stop),
+            refresher: Some(refresher),
+        })
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(refresher) = self.refresher.take() {
+            refresher.join().ok();
+        }
+
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("failed releasing lock {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl Lock {
+    /// Whether another instance held the lock before, which means it probably did the work
+    /// already.
+    pub const fn waited(&self) -> bool {
+        self.waited
+    }
+}
+
+/// Write to a temporary file first and move it in place, so other instances never read a
+/// partially written file.
+pub fn write_atomic(path: &Path, value: &[u8], private: bool) -> Result<()> {
+    let tmp = path.with_extension(format!("tmp{}", process::id()));
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(&tmp)?.write_all(value)?;
+    fs::rename(tmp, path).map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shared_storage(name: &str) -> Storage {
+        let path = std::env::temp_dir().join(format!("charon-{name}-{}", process::id()));
+        fs::remove_dir_all(&path).ok();
+
+        Storage::new(&settings::Storage::Shared { path }).unwrap()
+    }
+
+    fn set_age(path: &Path, age: Duration) {
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() - age)
+            .unwrap();
+    }
+
+    #[test]
+    fn lock_released_on_drop() {
+        let storage = shared_storage("lock-release");
+        let path = storage.dir().join("locks/cert.lock");
+
+        let lock = storage.lock("cert").unwrap();
+        assert!(path.exists());
+        assert!(!lock.waited());
+
+        drop(lock);
+        assert!(!path.exists());
+
+        fs::remove_dir_all(storage.dir()).ok();
+    }
+
+    #[test]
+    fn abandoned_lock_taken_over() {
+        let storage = shared_storage("lock-abandoned");
+        let path = storage.dir().join("locks/cert.lock");
+        fs::write(&path, "1").unwrap();
+        set_age(&path, LOCK_TIMEOUT * 2);
+
+        let lock = storage.lock("cert").unwrap();
+        assert!(!lock.waited());
+        assert_eq!(
+            process::id().to_string(),
+            fs::read_to_string(&path).unwrap()
+        );
+        assert_eq!(
+            1,
+            fs::read_dir(storage.dir().join("locks")).unwrap().count()
+        );
+
+        drop(lock);
+        fs::remove_dir_all(storage.dir()).ok();
+    }
+
+    #[test]
+    fn fresh_lock_moved_back() {
+        let storage = shared_storage("lock-fresh");
+        let path = storage.dir().join("locks/cert.lock");
+        fs::write(&path, "1").unwrap();
+
+        take_over(&path);
+
+        assert_eq!("1", fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            1,
+            fs::read_dir(storage.dir().join("locks")).unwrap().count()
+        );
+
+        fs::remove_dir_all(storage.dir()).ok();
+    }
+}
@@ -0,0 +1,292 @@
+//! Session ticket encryption with rotating keys, which are optionally shared with other instances
+//! through a key file.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use eyre::{ensure, eyre, Result};
+use log::{info, warn};
+use openssl::{
+    rand::rand_bytes,
+    symm::{self, Cipher},
+};
+use parking_lot::RwLock;
+use rustls::server::ProducesTickets;
+
+use crate::{
+    settings,
+    storage::{self, Storage},
+};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// Interval to check whether the keys are due for rotation or the key file changed.
+const CHECK_INTERVAL: Duration = Duration::from_mins(1);
+/// Name of the storage lock that instances take to rotate the keys in the key file.
+const KEY_FILE_LOCK: &str = "ticket-keys";
+
+pub struct Ticketer {
+    rotation: Duration,
+    key_file: Option<PathBuf>,
+    storage: Storage,
+    keys: RwLock<Keys>,
+}
+
+/// Ticket keys, with the first one used for new tickets and the others only for decrypting
+/// tickets that were issued before the last rotation.
+struct Keys {
+    keys: Vec<[u8; KEY_LEN]>,
+    rotated_at: SystemTime,
+}
+
+impl Ticketer {
+    pub fn new(settings: &settings::Sessions, storage: Storage) -> Result<Self> {
+        let ticketer = Self {
+            rotation: Duration::from_secs(settings.ticket_rotation),
+            key_file: settings.ticket_key_file.clone(),
+            storage,
+            keys: RwLock::new(Keys {
+                keys: vec![random_key()?],
+                rotated_at: SystemTime::now(),
+            }),
+        };
+
+        ticketer.refresh()?;
+
+        Ok(ticketer)
+    }
+
+    /// Rotate the keys on schedule and pick up keys that other instances wrote to the key file.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            // Waiting for the lock of the key file blocks.
+            let ticketer = self.clone();
+            let result = tokio::task::spawn_blocking(move || ticketer.refresh())
+                .await
+                .map_err(eyre::Report::from)
+                .and_then(|result| result);
+
+            if let Err(e) = result {
+                warn!("failed refreshing session ticket keys: {:?}", e);
+            }
+        }
+    }
+
+    fn refresh(&self) -> Result<()> {
+        let Some(path) = &self.key_file else {
+            let due = self.keys.read().rotated_at + self.rotation <= SystemTime::now();
+            if due {
+                let mut keys = self.keys.write();
+                *keys = keys.rotate()?;
+            }
+
+            return Ok(());
+        };
+
+        let is_due = |modified: Option<SystemTime>| {
+            modified.is_none_or(|modified| modified + self.rotation <= SystemTime::now())
+        };
+
+        if is_due(modified_time(path)) {
+            let _lock = self.storage.lock(KEY_FILE_LOCK)?;
+
+            // Another instance might have rotated the keys while this one waited for the lock.
+            let modified = modified_time(path);
+            if is_due(modified) {
+                let rotated = match modified {
+                    Some(modified) => Keys::load(path, modified)?.rotate()?,
+                    None => self.keys.read().rotate()?,
+                };
+                return self.save(path, &rotated);
+            }
+        }
+
+        if let Some(modified) = modified_time(path) {
+            if modified != self.keys.read().rotated_at {
+                *self.keys.write() = Keys::load(path, modified)?;
+                info!("loaded session ticket keys from {}", path.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn save(&self, path: &Path, keys: &Keys) -> Result<()> {
+        let data = keys.keys.iter().fold(String::new(), |mut data, key| {
+            data.push_str(&STANDARD.encode(key));
+            data.push('\n');
+            data
+        });
+
+        storage::write_atomic(path, data.as_bytes(), true)?;
+
+        // Track the file's own timestamp, so the next check doesn't consider it changed.
+        let modified = fs::metadata(path)?.modified()?;
+        *self.keys.write() = Keys {
+            keys: keys.keys.clone(),
+            rotated_at: modified,
+        };
+
+        info!("rotated session ticket keys in {}", path.display());
+
+        Ok(())
+    }
+}
+
+impl Keys {
+    fn load(path: &Path, modified: SystemTime) -> Result<Self> {
+        let keys = fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let key = STANDARD.decode(line.trim())?;
+                <[u8; KEY_LEN]>::try_from(key.as_slice())
+                    .map_err(|_| eyre!("session ticket keys must be {KEY_LEN} bytes long"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        ensure!(
+            !keys.is_empty(),
+            "no session ticket keys in {}",
+            path.display()
+        );
+
+        Ok(Self {
+            keys,
+            rotated_at: modified,
+        })
+    }
+
+    /// New key for encryption, keeping the current one to decrypt existing tickets.
+    fn rotate(&self) -> Result<Self> {
+        let mut keys = vec![random_key()?];
+        keys.extend(self.keys.first());
+
+        Ok(Self {
+            keys,
+            rotated_at: SystemTime::now(),
+        })
+    }
+}
+
+impl ProducesTickets for Ticketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        u32::try_from(self.rotation.as_secs()).unwrap_or(u32::MAX)
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let key = *self.keys.read().keys.first()?;
+
+        let mut nonce = [0; NONCE_LEN];
+        rand_bytes(&mut nonce).ok()?;
+        let mut tag = [0; TAG_LEN];
+        let cipher = symm::encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key,
+            Some(&nonce),
+            &[],
+            plain,
+            &mut tag,
+        )
+        .ok()?;
+
+        Some([&nonce[..], &cipher, &tag].concat())
+    }
+
+    fn decrypt(&self, ticket: &[u8]) -> Option<Vec<u8>> {
+        if ticket.len() < NONCE_LEN + TAG_LEN {
+            return None;
+        }
+
+        let (nonce, rest) = ticket.split_at(NONCE_LEN);
+        let (cipher, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+        self.keys.read().keys.iter().find_map(|key| {
+            symm::decrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), &[], cipher, tag).ok()
+        })
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+fn random_key() -> Result<[u8; KEY_LEN]> {
+    let mut key = [0; KEY_LEN];
+    rand_bytes(&mut key)?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, process};
+
+    use super::*;
+
+    fn ticketer(name: &str) -> (Ticketer, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("charon-{name}-{}", process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let storage = Storage::new(&settings::Storage::Shared { path: dir.clone() }).unwrap();
+
+        let settings = settings::Sessions {
+            ticket_key_file: Some(dir.join("ticket-keys")),
+            ..settings::Sessions::default()
+        };
+
+        (Ticketer::new(&settings, storage).unwrap(), dir)
+    }
+
+    #[test]
+    fn keys_shared_through_file() {
+        let (first, dir) = ticketer("tickets-shared");
+        let second = Ticketer::new(
+            &settings::Sessions {
+                ticket_key_file: first.key_file.clone(),
+                ..settings::Sessions::default()
+            },
+            first.storage.clone(),
+        )
+        .unwrap();
+
+        let ticket = first.encrypt(b"session").unwrap();
+        assert_eq!(b"session".as_slice(), second.decrypt(&ticket).unwrap());
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn due_keys_rotated_under_lock() {
+        let (ticketer, dir) = ticketer("tickets-rotate");
+        let path = ticketer.key_file.clone().unwrap();
+        let ticket = ticketer.encrypt(b"session").unwrap();
+
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() - ticketer.rotation * 2)
+            .unwrap();
+        ticketer.refresh().unwrap();
+
+        assert_eq!(2, fs::read_to_string(&path).unwrap().lines().count());
+        assert!(!dir.join("locks/ticket-keys.lock").exists());
+        assert_eq!(b"session".as_slice(), ticketer.decrypt(&ticket).unwrap());
+        assert_ne!(ticket, ticketer.encrypt(b"session").unwrap());
+
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -1,114 +1,560 @@
 //! TLS impelementation for [`hyper`] mostly copied from [`warp`](https://crates.io/crates/warp).
 
 use std::{
-    future::Future,
-    net::SocketAddr,
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    net::{IpAddr, SocketAddr},
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
+    time::Duration,
 };
 
-use eyre::Result;
-use futures_util::ready;
+use ahash::RandomState;
+use eyre::{ensure, eyre, Result, WrapErr};
 use hyper::server::{
     accept::Accept,
     conn::{AddrIncoming, AddrStream},
 };
-use rustls::ServerConfig;
-use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use log::{debug, info, warn};
+use rustls::{
+    server::{
+        AllowAnyAnonymousOrAuthenticatedClient, NoServerSessionStorage, ProducesTickets,
+        ResolvesServerCert, ServerSessionMemoryCache, StoresServerSessions,
+    },
+    version::{TLS12, TLS13},
+    ConfigBuilder, ProtocolVersion, RootCertStore, ServerConfig, ServerConnection,
+    SupportedCipherSuite, SupportedKxGroup, WantsVerifier, ALL_CIPHER_SUITES, ALL_KX_GROUPS,
+    DEFAULT_CIPHER_SUITES,
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    sync::mpsc,
+    time::Instant,
+};
+use tokio_rustls::LazyConfigAcceptor;
+use x509_parser::extensions::GeneralName;
+
+use crate::{
+    cert, passthrough, proxy_protocol,
+    settings::{self, ClientAuths, Routes, TlsPolicy, TlsVersion},
+    shutdown::{Guard, Shutdown},
+    tickets::Ticketer,
+};
+
+/// Protocols offered through ALPN. Requests are forwarded to the upstreams over HTTP/1.1, so
+/// HTTP/2 isn't offered yet.
+const ALPN_PROTOCOLS: &[&[u8]] = &[b"http/1.1"];
+
+/// Server configurations that are selected by the SNI name, as the TLS policy and client
+/// authentication can only be set for a whole configuration.
+pub struct TlsConfigs {
+    default: Arc<ServerConfig>,
+    by_host: HashMap<String, Arc<ServerConfig>, RandomState>,
+}
+
+impl TlsConfigs {
+    pub fn new(
+        resolver: Arc<dyn ResolvesServerCert>,
+        tls: &settings::Tls,
+        client_auth: &ClientAuths,
+        sessions: &settings::Sessions,
+        ticketer: Option<Arc<Ticketer>>,
+    ) -> Result<Self> {
+        // All configurations share the same session state, as a session is only resumed for the
+        // SNI name it was established with, which always selects the same configuration.
+        let session_storage: Arc<dyn StoresServerSessions> = if sessions.cache_size == 0 {
+            Arc::new(NoServerSessionStorage {})
+        } else {
+            ServerSessionMemoryCache::new(sessions.cache_size)
+        };
+        let ticketer = ticketer.map(|t| t as Arc<dyn ProducesTickets>);
+        let finish = |mut config: ServerConfig| {
+            config.alpn_protocols = ALPN_PROTOCOLS.iter().map(|p| p.to_vec()).collect();
+            config.session_storage = session_storage.clone();
+            if let Some(ticketer) = &ticketer {
+                config.ticketer = ticketer.clone();
+            }
+            Arc::new(config)
+        };
+
+        let by_host = client_auth
+            .keys()
+            .chain(tls.hosts.keys())
+            .map(|host| {
+                let policy = cert::lookup(&tls.hosts, host)
+                    .map_or_else(|| tls.policy.clone(), |p| tls.policy.merge(p));
+                let config =
+                    server_config(&policy, cert::lookup(client_auth, host), resolver.clone())
+                        .wrap_err_with(|| format!("invalid TLS settings for {host}"))?;
+
+                Ok((host.clone(), finish(config)))
+            })
+            .collect::<Result<_>>()?;
+
+        let default =
+            server_config(&tls.policy, None, resolver).wrap_err("invalid TLS settings")?;
+
+        Ok(Self {
+            default: finish(default),
+            by_host,
+        })
+    }
 
-enum State {
-    Handshaking(tokio_rustls::Accept<AddrStream>),
-    Streaming(tokio_rustls::server::TlsStream<AddrStream>),
+    fn select(&self, server_name: Option<&str>) -> Arc<ServerConfig> {
+        server_name
+            .and_then(|name| cert::lookup(&self.by_host, &name.to_ascii_lowercase()))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+fn server_config(
+    policy: &TlsPolicy,
+    client_auth: Option<&settings::ClientAuth>,
+    resolver: Arc<dyn ResolvesServerCert>,
+) -> Result<ServerConfig> {
+    let builder = config_builder(policy)?;
+
+    let Some(auth) = client_auth else {
+        return Ok(builder.with_no_client_auth().with_cert_resolver(resolver));
+    };
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&auth.ca)?))?;
+
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(&certs);
+    ensure!(
+        added > 0,
+        "no valid CA certificates in {}",
+        auth.ca.display()
+    );
+
+    // Clients without a certificate pass the handshake in both modes. A connection can serve
+    // other hosts than its SNI name, so `Require` is enforced per request, where clients get a
+    // proper error status instead of a failed handshake.
+    Ok(builder
+        .with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+        .with_cert_resolver(resolver))
 }
 
+fn config_builder(policy: &TlsPolicy) -> Result<ConfigBuilder<ServerConfig, WantsVerifier>> {
+    let versions = policy
+        .versions
+        .clone()
+        .unwrap_or_else(|| policy.profile.unwrap_or_default().versions())
+        .into_iter()
+        .map(|version| match version {
+            TlsVersion::Tls12 => &TLS12,
+            TlsVersion::Tls13 => &TLS13,
+        })
+        .collect::<Vec<_>>();
+
+    let cipher_suites = match &policy.cipher_suites {
+        Some(names) => names
+            .iter()
+            .map(|name| {
+                find_by_name(
+                    "cipher suite",
+                    ALL_CIPHER_SUITES,
+                    name,
+                    |s: &SupportedCipherSuite| format!("{:?}", s.suite()),
+                )
+            })
+            .collect::<Result<_>>()?,
+        None => DEFAULT_CIPHER_SUITES.to_vec(),
+    };
+
+    let kx_groups = match &policy.kx_groups {
+        Some(names) => names
+            .iter()
+            .map(|name| {
+                find_by_name(
+                    "key exchange group",
+                    &ALL_KX_GROUPS,
+                    name,
+                    |g: &&SupportedKxGroup| format!("{:?}", g.name),
+                )
+            })
+            .collect::<Result<_>>()?,
+        None => ALL_KX_GROUPS.to_vec(),
+    };
+
+    ServerConfig::builder()
+        .with_cipher_suites(&cipher_suites)
+        .with_kx_groups(&kx_groups)
+        .with_protocol_versions(&versions)
+        .map_err(Into::into)
+}
+
+fn find_by_name<T: Copy>(
+    kind: &str,
+    all: &[T],
+    name: &str,
+    name_of: impl Fn(&T) -> String,
+) -> Result<T> {
+    all.iter()
+        .find(|item| name_of(item).eq_ignore_ascii_case(name))
+        .copied()
+        .ok_or_else(|| eyre!("unsupported {kind} `{name}`"))
+}
+
+/// Details about a connection, available once the handshake completed.
+pub struct ConnInfo {
+    pub server_name: Option<String>,
+    /// Application protocol that was negotiated through ALPN.
+    pub alpn_protocol: Option<String>,
+    pub version: &'static str,
+    /// Client certificate, which the verifier of the selected configuration accepted.
+    pub client_cert: Option<ClientCert>,
+}
+
+impl ConnInfo {
+    fn new(conn: &ServerConnection) -> Self {
+        Self {
+            server_name: conn.sni_hostname().map(str::to_ascii_lowercase),
+            alpn_protocol: conn
+                .alpn_protocol()
+                .map(|proto| String::from_utf8_lossy(proto).into_owned()),
+            version: match conn.protocol_version() {
+                Some(ProtocolVersion::TLSv1_2) => "TLSv1.2",
+                Some(ProtocolVersion::TLSv1_3) => "TLSv1.3",
+                _ => "unknown",
+            },
+            client_cert: conn
+                .peer_certificates()
+                .and_then(<[_]>::first)
+                .and_then(|cert| ClientCert::parse(&cert.0)),
+        }
+    }
+}
+
+pub struct ClientCert {
+    pub subject: String,
+    /// Subject alternative names like DNS names, email addresses, URIs and IP addresses.
+    pub sans: Vec<String>,
+}
+
+impl ClientCert {
+    fn parse(der: &[u8]) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+
+        let sans = cert
+            .tbs_certificate
+            .subject_alternative_name()
+            .map(|(_, san)| {
+                san.general_names
+                    .iter()
+                    .filter_map(|name| match name {
+                        GeneralName::DNSName(v)
+                        | GeneralName::RFC822Name(v)
+                        | GeneralName::URI(v) => Some((*v).to_owned()),
+                        GeneralName::IPAddress(bytes) => match bytes.len() {
+                            4 => Some(IpAddr::from(<[u8; 4]>::try_from(*bytes).ok()?).to_string()),
+                            16 => {
+                                Some(IpAddr::from(<[u8; 16]>::try_from(*bytes).ok()?).to_string())
+                            }
+                            _ => None,
+                        },
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Some(Self {
+            subject: cert.subject().to_string(),
+            sans,
+        })
+    }
+}
+
+/// Reason of a failed handshake.
+#[derive(Clone, Copy)]
+enum Failure {
+    /// No certificate for the SNI name and no fallback to serve instead.
+    UnknownSni,
+    /// No protocol version, cipher suite, key exchange group or application protocol in common
+    /// with the client.
+    ProtocolMismatch,
+    /// Missing or invalid client certificate.
+    ClientCert,
+    Timeout,
+    /// Anything else, like clients that don't speak TLS or close the connection early.
+    Other,
+}
+
+impl Failure {
+    const ALL: [Self; 5] = [
+        Self::UnknownSni,
+        Self::ProtocolMismatch,
+        Self::ClientCert,
+        Self::Timeout,
+        Self::Other,
+    ];
+
+    fn classify(error: &io::Error) -> Self {
+        let Some(error) = error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<rustls::Error>())
+        else {
+            return Self::Other;
+        };
+
+        match error {
+            // The resolver returns nothing for unknown names if the fallback is set to reject.
+            rustls::Error::General(msg) if msg.contains("no server certificate") => {
+                Self::UnknownSni
+            }
+            rustls::Error::PeerIncompatibleError(_) | rustls::Error::NoApplicationProtocol => {
+                Self::ProtocolMismatch
+            }
+            rustls::Error::NoCertificatesPresented
+            | rustls::Error::InvalidCertificateEncoding
+            | rustls::Error::InvalidCertificateSignatureType
+            | rustls::Error::InvalidCertificateSignature
+            | rustls::Error::InvalidCertificateData(_) => Self::ClientCert,
+            _ => Self::Other,
+        }
+    }
+
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::UnknownSni => "unknown SNI",
+            Self::ProtocolMismatch => "protocol mismatch",
+            Self::ClientCert => "client certificate",
+            Self::Timeout => "timeout",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Counters of failed handshakes by reason.
+#[derive(Default)]
+pub struct HandshakeFailures {
+    counts: [AtomicU64; Failure::ALL.len()],
+}
+
+impl HandshakeFailures {
+    fn record(&self, failure: Failure, remote_addr: SocketAddr, error: &io::Error) {
+        self.counts[failure as usize].fetch_add(1, Ordering::Relaxed);
+        debug!(
+            "TLS handshake with {} failed ({}): {}",
+            remote_addr,
+            failure.as_str(),
+            error
+        );
+    }
+
+    /// Log the failures of the last interval every few minutes, if there were any.
+    pub async fn report(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_mins(10));
+
+        loop {
+            interval.tick().await;
+
+            let counts = Failure::ALL
+                .iter()
+                .filter_map(|&failure| {
+                    let count = self.counts[failure as usize].swap(0, Ordering::Relaxed);
+                    (count > 0).then(|| format!("{} {}", failure.as_str(), count))
+                })
+                .collect::<Vec<_>>();
+
+            if !counts.is_empty() {
+                info!("failed TLS handshakes: {}", counts.join(", "));
+            }
+        }
+    }
+}
+
+/// TLS connection with a completed handshake.
 pub struct TlsStream {
-    state: State,
+    stream: tokio_rustls::server::TlsStream<AddrStream>,
+    /// Address of the client, which a load balancer might have passed on through the PROXY
+    /// protocol.
     pub remote_addr: SocketAddr,
+    pub local_addr: SocketAddr,
+    pub info: Arc<ConnInfo>,
+    /// Counts the connection as active for graceful shutdowns, including once it's upgraded to a
+    /// tunnel.
+    active: Guard,
 }
 
 impl TlsStream {
-    fn new(stream: AddrStream, config: Arc<ServerConfig>) -> Self {
-        let remote_addr = stream.remote_addr();
-        let accept = tokio_rustls::TlsAcceptor::from(config).accept(stream);
-        Self {
-            state: State::Handshaking(accept),
+    /// Perform the handshake, first picking the configuration for the SNI name of the client
+    /// hello.
+    async fn accept(
+        stream: AddrStream,
+        remote_addr: SocketAddr,
+        configs: Arc<TlsConfigs>,
+        deadline: Instant,
+        active: Guard,
+    ) -> Result<Self, (Failure, io::Error)> {
+        let local_addr = stream.local_addr();
+
+        let handshake = async {
+            let start =
+                LazyConfigAcceptor::new(rustls::server::Acceptor::default(), stream).await?;
+            let config = configs.select(start.client_hello().server_name());
+            start.into_stream(config).await
+        };
+
+        let stream = match tokio::time::timeout_at(deadline, handshake).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => return Err((Failure::classify(&e), e)),
+            Err(_) => return Err((Failure::Timeout, timed_out())),
+        };
+
+        Ok(Self {
+            info: Arc::new(ConnInfo::new(stream.get_ref().1)),
+            stream,
             remote_addr,
-        }
+            local_addr,
+            active,
+        })
     }
 }
 
 impl AsyncRead for TlsStream {
     fn poll_read(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
-        let pin = self.get_mut();
-        match pin.state {
-            State::Handshaking(ref mut accept) => match ready!(Pin::new(accept).poll(cx)) {
-                Ok(mut stream) => {
-                    let result = Pin::new(&mut stream).poll_read(cx, buf);
-                    pin.state = State::Streaming(stream);
-                    result
-                }
-                Err(err) => Poll::Ready(Err(err)),
-            },
-            State::Streaming(ref mut stream) => Pin::new(stream).poll_read(cx, buf),
-        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
     }
 }
 
 impl AsyncWrite for TlsStream {
     fn poll_write(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
-        let pin = self.get_mut();
-        match pin.state {
-            State::Handshaking(ref mut accept) => match ready!(Pin::new(accept).poll(cx)) {
-                Ok(mut stream) => {
-                    let result = Pin::new(&mut stream).poll_write(cx, buf);
-                    pin.state = State::Streaming(stream);
-                    result
-                }
-                Err(err) => Poll::Ready(Err(err)),
-            },
-            State::Streaming(ref mut stream) => Pin::new(stream).poll_write(cx, buf),
-        }
+        Pin::new(&mut self.stream).poll_write(cx, buf)
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        match self.state {
-            State::Handshaking(_) => Poll::Ready(Ok(())),
-            State::Streaming(ref mut stream) => Pin::new(stream).poll_flush(cx),
-        }
+        Pin::new(&mut self.stream).poll_flush(cx)
     }
 
     fn poll_shutdown(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), io::Error>> {
-        match self.state {
-            State::Handshaking(_) => Poll::Ready(Ok(())),
-            State::Streaming(ref mut stream) => Pin::new(stream).poll_shutdown(cx),
-        }
+        Pin::new(&mut self.stream).poll_shutdown(cx)
     }
 }
 
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out")
+}
+
+/// Acceptor that runs the handshake of every new connection in its own task, so slow clients
+/// don't hold up others, and only hands over connections once their handshake completed.
+/// Connections for passthrough hosts are forwarded as they are and never reach hyper.
 pub struct TlsAcceptor {
-    config: Arc<ServerConfig>,
+    configs: Arc<TlsConfigs>,
     incoming: AddrIncoming,
+    passthrough: Arc<Routes>,
+    proxy_protocol: Arc<settings::ProxyProtocol>,
+    handshake_timeout: Duration,
+    failures: Arc<HandshakeFailures>,
+    shutdown: Shutdown,
+    established_tx: mpsc::UnboundedSender<TlsStream>,
+    established_rx: mpsc::UnboundedReceiver<TlsStream>,
 }
 
 impl TlsAcceptor {
-    pub fn new(config: ServerConfig, incoming: AddrIncoming) -> Self {
+    pub fn new(
+        configs: TlsConfigs,
+        incoming: AddrIncoming,
+        passthrough: Routes,
+        proxy_protocol: Arc<settings::ProxyProtocol>,
+        handshake_timeout: Duration,
+        shutdown: Shutdown,
+    ) -> Self {
+        let (established_tx, established_rx) = mpsc::unbounded_channel();
+
         Self {
-            config: Arc::new(config),
+            configs: Arc::new(configs),
             incoming,
+            passthrough: Arc::new(passthrough),
+            proxy_protocol,
+            handshake_timeout,
+            failures: Arc::default(),
+            shutdown,
+            established_tx,
+            established_rx,
         }
     }
+
+    pub fn failures(&self) -> Arc<HandshakeFailures> {
+        self.failures.clone()
+    }
+
+    fn spawn_handshake(&self, mut stream: AddrStream) {
+        let mut remote_addr = stream.remote_addr();
+        let configs = self.configs.clone();
+        let passthrough = self.passthrough.clone();
+        let proxy_protocol = self.proxy_protocol.clone();
+        let deadline = Instant::now() + self.handshake_timeout;
+        let failures = self.failures.clone();
+        let established = self.established_tx.clone();
+        let active = self.shutdown.track();
+
+        tokio::spawn(async move {
+            if proxy_protocol.https && proxy_protocol.trusts(remote_addr.ip()) {
+                match tokio::time::timeout_at(deadline, proxy_protocol::read_header(&mut stream))
+                    .await
+                {
+                    Ok(Ok(addr)) => remote_addr = addr.unwrap_or(remote_addr),
+                    Ok(Err(e)) => return failures.record(Failure::Other, remote_addr, &e),
+                    Err(_) => return failures.record(Failure::Timeout, remote_addr, &timed_out()),
+                }
+            }
+
+            if !passthrough.is_empty() {
+                let upstream =
+                    match tokio::time::timeout_at(deadline, passthrough::server_name(&mut stream))
+                        .await
+                    {
+                        Ok(Ok(name)) => {
+                            name.and_then(|name| cert::lookup(&passthrough, &name).cloned())
+                        }
+                        Ok(Err(e)) => return failures.record(Failure::Other, remote_addr, &e),
+                        Err(_) => {
+                            return failures.record(Failure::Timeout, remote_addr, &timed_out())
+                        }
+                    };
+
+                if let Some(upstream) = upstream {
+                    let header = proxy_protocol.upstreams.get(&upstream).map(|&version| {
+                        proxy_protocol::header(version, remote_addr, stream.local_addr())
+                    });
+
+                    if let Err(e) =
+                        passthrough::splice(stream, remote_addr, &upstream, header).await
+                    {
+                        warn!("passthrough to {} failed: {}", upstream, e);
+                    }
+                    return;
+                }
+            }
+
+            match TlsStream::accept(stream, remote_addr, configs, deadline, active).await {
+                Ok(stream) => {
+                    established.send(stream).ok();
+                }
+                Err((failure, e)) => failures.record(failure, remote_addr, &e),
+            }
+        });
+    }
 }
 
 impl Accept for TlsAcceptor {
@@ -120,10 +566,18 @@ impl Accept for TlsAcceptor {
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
         let pin = self.get_mut();
-        match ready!(Pin::new(&mut pin.incoming).poll_accept(cx)) {
-            Some(Ok(sock)) => Poll::Ready(Some(Ok(TlsStream::new(sock, pin.config.clone())))),
-            Some(Err(e)) => Poll::Ready(Some(Err(e))),
-            None => Poll::Ready(None),
+
+        loop {
+            match Pin::new(&mut pin.incoming).poll_accept(cx) {
+                Poll::Ready(Some(Ok(sock))) => pin.spawn_handshake(sock),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => break,
+            }
         }
+
+        pin.established_rx
+            .poll_recv(cx)
+            .map(|stream| stream.map(Ok))
     }
 }